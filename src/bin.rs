@@ -1,89 +1,114 @@
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
 use structopt::StructOpt;
 
+use lovm2::module::Module;
+
+use lol::build::{build, BuildReport};
+use lol::diagnostic::Diagnostic;
 use lol::interpreter::Interpreter;
+use lol::manifest::Manifest;
+use lol::repl::Repl;
 use lol::transpiler::Transpiler;
-use lol::{LOLC_EXTENSION, LOL_EXTENSION};
-
-fn find_files<T>(path: T, files: &mut Vec<PathBuf>) -> std::io::Result<()>
-where
-    T: AsRef<Path>,
-{
-    for entry in std::fs::read_dir(path)? {
-        let entry = entry?;
+use lol::util::lol_path;
+use lol::LOL_EXTENSION;
 
-        if let Some(ext) = entry.path().extension() {
-            if ext == LOL_EXTENSION {
-                files.push(entry.path().clone());
-            }
-        }
+/// Print the modules compiled by a build and their warnings.
+fn print_report(report: &BuildReport) {
+    for name in report.rebuilt.iter() {
+        println!("built {}", name);
     }
-
-    Ok(())
-}
-
-fn build(path: PathBuf) -> Option<PathBuf> {
-    use lovm2::prelude::ENTRY_POINT;
-
-    let mut files = vec![];
-    let mut entry_point = None;
-
-    let mut srcdir = path.clone();
-    srcdir.push("src");
-    find_files(&srcdir, &mut files).unwrap();
-
-    let mut targetdir = path;
-    targetdir.push("target");
-    targetdir.push("lol");
-    std::fs::create_dir_all(&targetdir).unwrap();
-
-    let mut transpiler = Transpiler::new();
-
-    for file in files.into_iter() {
-        let relname = file.strip_prefix(&srcdir).unwrap().clone();
-        let mut targetfile = targetdir.clone();
-        targetfile.push(relname);
-        targetfile.set_extension(LOLC_EXTENSION);
-
-        println!("building {} ...", file.to_str().unwrap());
-
-        let module = transpiler.build_from_path(file).unwrap();
-
-        module.store_to_file(&targetfile).unwrap();
-
-        if module.slot(&ENTRY_POINT.into()).is_some() {
-            entry_point = Some(targetfile);
-        }
+    if report.rebuilt.is_empty() {
+        println!("{} is up to date", report.package);
+    }
+    for warning in report.warnings.iter() {
+        eprint!(
+            "{}",
+            Diagnostic::from_compile_warning(warning).render_warning(None)
+        );
     }
-
-    entry_point
 }
 
-fn run<T>(path: Option<T>)
+fn run<T>(path: Option<T>) -> Result<(), Diagnostic>
 where
     T: AsRef<Path>,
 {
-    let mut int = Interpreter::new();
-
-    match path {
-        Some(path) => {
-            if let Err(e) = int.run_from_path(path) {
-                println!("{:?}", e);
-            }
+    let mut search_paths = vec![];
+
+    let (source_path, module) = match path {
+        Some(path) if !path.as_ref().is_dir() => {
+            let path = path.as_ref().to_path_buf();
+            let module = match path.extension() {
+                Some(ext) if ext == LOL_EXTENSION => {
+                    let mut transpiler = Transpiler::new();
+                    for dir in lol_path() {
+                        transpiler.add_search_path(dir);
+                    }
+                    let module = transpiler.build_from_path(&path).map_err(|e| {
+                        let source = std::fs::read_to_string(&path).ok();
+                        Diagnostic::from_compile_error(&e, source.as_deref())
+                    })?;
+                    for warning in transpiler.warnings() {
+                        eprint!(
+                            "{}",
+                            Diagnostic::from_compile_warning(warning).render_warning(None)
+                        );
+                    }
+                    module
+                }
+                _ => Module::load_from_file(&path)
+                    .map_err(|e| Diagnostic::from_runtime_error(&e, None, None))?,
+            };
+            (path, module)
         }
         _ => {
-            let dir = std::env::current_dir().unwrap();
-            match build(dir) {
-                Some(main) => {
-                    if let Err(e) = int.run_from_path(main) {
-                        println!("{}: {}", e.ty, e.msg);
-                    }
+            let dir = match path {
+                Some(path) => path.as_ref().to_path_buf(),
+                _ => std::env::current_dir().map_err(|e| Diagnostic {
+                    msg: format!("cannot access current directory: {}", e),
+                    loc: None,
+                    hint: None,
+                })?,
+            };
+            let manifest =
+                Manifest::from_dir(&dir).map_err(|e| Diagnostic::from_manifest_error(&e))?;
+            search_paths = manifest.search_dirs();
+
+            let report = build(dir)?;
+            print_report(&report);
+
+            match report.entry_point {
+                Some((source_path, main)) => {
+                    let module = Module::load_from_file(&main)
+                        .map_err(|e| Diagnostic::from_runtime_error(&e, None, None))?;
+                    (source_path, module)
+                }
+                _ => {
+                    return Err(Diagnostic {
+                        msg: "no entry point".to_string(),
+                        loc: None,
+                        hint: Some(
+                            "define a function called `main` or set `entry` in `lol.toml`"
+                                .to_string(),
+                        ),
+                    })
                 }
-                _ => println!("no entry point"),
             }
         }
+    };
+
+    let mut int = Interpreter::new();
+    for dir in search_paths.iter() {
+        int.add_search_path(dir);
+    }
+
+    if let Err(e) = int.load_main(module).and_then(|_| int.run()) {
+        let file = source_path.display().to_string();
+        let source = std::fs::read_to_string(&source_path).ok();
+        return Err(int.diagnose(&e, Some(file.as_ref()), source.as_deref()));
     }
+
+    Ok(())
 }
 
 #[derive(StructOpt)]
@@ -101,20 +126,27 @@ enum CliOptions {
     },
     #[structopt()]
     Run {
-        #[structopt(name = "FILE")]
+        #[structopt(name = "FILE_OR_DIRECTORY")]
         path: Option<String>,
     },
+    #[structopt()]
+    Repl,
 }
 
 fn main() {
     let args = CliOptions::from_args();
 
-    match args {
-        CliOptions::Build { path } => {
-            build(Path::new(&path).to_path_buf());
-        }
-        CliOptions::Run { path } => {
-            run(path.as_ref().map(Path::new));
+    let result = match args {
+        CliOptions::Build { path } => build(Path::new(&path)).map(|report| print_report(&report)),
+        CliOptions::Run { path } => run(path.as_ref().map(Path::new)),
+        CliOptions::Repl => {
+            Repl::new().run();
+            Ok(())
         }
+    };
+
+    if let Err(diagnostic) = result {
+        eprint!("{}", diagnostic);
+        std::process::exit(1);
     }
 }
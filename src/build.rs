@@ -0,0 +1,418 @@
+use lovm2::module::Module;
+use lovm2::prelude::ENTRY_POINT;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::diagnostic::Diagnostic;
+use crate::error::CompileWarning;
+use crate::manifest::Manifest;
+use crate::transpiler::Transpiler;
+use crate::util::{fnv1a, lol_path, module_name, module_path};
+use crate::{LOLC_EXTENSION, LOL_EXTENSION};
+
+/// File inside the output directory that records the state of the last build.
+pub const BUILD_STATE_FILE: &str = ".lol-build";
+
+/// Version of the build state layout. Has to be increased whenever the format
+/// of the state file changes.
+pub const BUILD_STATE_VERSION: u32 = 1;
+
+/// Stamp of the compiler and build state layout written on the first line of
+/// the state file. Output built with another stamp is compiled again.
+pub fn build_version() -> String {
+    format!("{}+{}", env!("CARGO_PKG_VERSION"), BUILD_STATE_VERSION)
+}
+
+/// Source file and compiled module of the entry point.
+pub type EntryPoint = (PathBuf, PathBuf);
+
+/// Outcome of a successful build.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BuildReport {
+    /// Name of the package that was built.
+    pub package: String,
+    pub entry_point: Option<EntryPoint>,
+    /// Names of the modules that were compiled. Modules that are up to date
+    /// are not listed.
+    pub rebuilt: Vec<String>,
+    /// Warnings emitted while compiling the rebuilt modules.
+    pub warnings: Vec<CompileWarning>,
+}
+
+fn io_error<T>(path: T, e: std::io::Error) -> Diagnostic
+where
+    T: AsRef<Path>,
+{
+    Diagnostic {
+        msg: format!("cannot access `{}`: {}", path.as_ref().display(), e),
+        loc: None,
+        hint: None,
+    }
+}
+
+/// Collect all source files inside `path` and its subdirectories.
+fn find_files<T>(path: T, files: &mut Vec<PathBuf>) -> std::io::Result<()>
+where
+    T: AsRef<Path>,
+{
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+
+        if entry.file_type()?.is_dir() {
+            find_files(entry.path(), files)?;
+        } else if let Some(ext) = entry.path().extension() {
+            if ext == LOL_EXTENSION {
+                files.push(entry.path().clone());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Source hash and imports of a module at the time it was compiled.
+#[derive(Clone, Debug, PartialEq)]
+struct ModuleState {
+    hash: u64,
+    uses: Vec<String>,
+}
+
+/// Modules compiled into an output directory. The state file starts with the
+/// [build_version]. Every other line contains the module name, its source hash
+/// and the modules it imports.
+#[derive(Debug, Default)]
+struct BuildState {
+    modules: HashMap<String, ModuleState>,
+}
+
+impl BuildState {
+    /// Read the state of the last build. A missing or damaged file or one
+    /// written by another version causes a full rebuild.
+    fn load(outdir: &Path) -> Self {
+        let mut state = Self::default();
+        let content = match std::fs::read_to_string(outdir.join(BUILD_STATE_FILE)) {
+            Ok(content) => content,
+            _ => return state,
+        };
+
+        let mut lines = content.lines();
+        if lines.next() != Some(build_version().as_str()) {
+            return state;
+        }
+
+        for line in lines {
+            let mut parts = line.split_whitespace();
+            let name = parts.next();
+            let hash = parts.next().map(|hash| u64::from_str_radix(hash, 16));
+
+            match (name, hash) {
+                (Some(name), Some(Ok(hash))) => {
+                    let uses = parts.map(str::to_string).collect();
+                    state
+                        .modules
+                        .insert(name.to_string(), ModuleState { hash, uses });
+                }
+                _ => return Self::default(),
+            }
+        }
+
+        state
+    }
+
+    fn store(&self, outdir: &Path) -> std::io::Result<()> {
+        let mut names: Vec<&String> = self.modules.keys().collect();
+        names.sort();
+
+        let mut content = build_version();
+        content.push('\n');
+        for name in names.into_iter() {
+            let module = &self.modules[name];
+            content.push_str(&format!("{} {:016x}", name, module.hash));
+            for used in module.uses.iter() {
+                content.push(' ');
+                content.push_str(used);
+            }
+            content.push('\n');
+        }
+
+        std::fs::write(outdir.join(BUILD_STATE_FILE), content)
+    }
+}
+
+/// Build the project inside `path` together with its dependencies. The project
+/// layout is taken from `lol.toml` if present. Modules are only compiled if
+/// their source or one of the modules they import changed since the last build.
+pub fn build<T>(path: T) -> Result<BuildReport, Diagnostic>
+where
+    T: AsRef<Path>,
+{
+    let manifest = Manifest::from_dir(path).map_err(|e| Diagnostic::from_manifest_error(&e))?;
+    let outdir = manifest.output_dir();
+    std::fs::create_dir_all(&outdir).map_err(|e| io_error(&outdir, e))?;
+
+    let mut builder = Builder {
+        outdir: outdir.clone(),
+        state: BuildState::load(&outdir),
+        rebuilt: vec![],
+        warnings: vec![],
+        visited: vec![],
+        sources: vec![],
+    };
+
+    let result = builder
+        .build_dependencies(&manifest)
+        .and_then(|_| builder.build_package(&manifest))
+        .and_then(|entry_point| builder.prune().map(|_| entry_point));
+
+    // keep track of the modules compiled so far even if the build failed
+    builder
+        .state
+        .store(&outdir)
+        .map_err(|e| io_error(outdir.join(BUILD_STATE_FILE), e))?;
+
+    let entry_point = result?;
+
+    match (&manifest.entry, &entry_point) {
+        (Some(entry), None) => Err(Diagnostic {
+            msg: format!("entry module `{}` not found", entry),
+            loc: None,
+            hint: Some("check `entry` in `lol.toml`".to_string()),
+        }),
+        _ => Ok(BuildReport {
+            package: manifest.name,
+            entry_point,
+            rebuilt: builder.rebuilt,
+            warnings: builder.warnings,
+        }),
+    }
+}
+
+/// A source file of the package being built.
+struct SourceFile {
+    name: String,
+    path: PathBuf,
+    target: PathBuf,
+    hash: u64,
+}
+
+struct Builder {
+    outdir: PathBuf,
+    state: BuildState,
+    rebuilt: Vec<String>,
+    warnings: Vec<CompileWarning>,
+    visited: Vec<PathBuf>,
+    /// Names of all modules found in the built packages.
+    sources: Vec<String>,
+}
+
+impl Builder {
+    /// Dependencies are compiled into the output directory of the depending
+    /// package so they can be found at runtime.
+    fn build_dependencies(&mut self, manifest: &Manifest) -> Result<(), Diagnostic> {
+        for dependency in manifest.dependencies.iter() {
+            let root = manifest.dependency_dir(dependency);
+            let root = root.canonicalize().map_err(|e| io_error(&root, e))?;
+            if self.visited.contains(&root) {
+                continue;
+            }
+            self.visited.push(root.clone());
+
+            let package =
+                Manifest::from_dir(&root).map_err(|e| Diagnostic::from_manifest_error(&e))?;
+            self.build_dependencies(&package)?;
+            self.build_package(&package)?;
+        }
+
+        Ok(())
+    }
+
+    fn find_sources(&self, manifest: &Manifest) -> Result<Vec<SourceFile>, Diagnostic> {
+        let mut sources = vec![];
+
+        for srcdir in manifest.source_dirs().into_iter() {
+            let mut files = vec![];
+            find_files(&srcdir, &mut files).map_err(|e| io_error(&srcdir, e))?;
+
+            for path in files.into_iter() {
+                let relname = path.strip_prefix(&srcdir).unwrap();
+                let mut target = self.outdir.clone();
+                target.push(relname);
+                target.set_extension(LOLC_EXTENSION);
+
+                let source = std::fs::read(&path).map_err(|e| io_error(&path, e))?;
+
+                sources.push(SourceFile {
+                    name: module_name(relname),
+                    hash: fnv1a(source),
+                    path,
+                    target,
+                });
+            }
+        }
+
+        Ok(sources)
+    }
+
+    /// Determine which sources have to be compiled. A module is outdated if its
+    /// source changed or one of the modules it imports is rebuilt.
+    fn outdated(&self, sources: &[SourceFile]) -> Vec<bool> {
+        let mut outdated: Vec<bool> = sources
+            .iter()
+            .map(|source| match self.state.modules.get(&source.name) {
+                Some(state) => state.hash != source.hash || !source.target.exists(),
+                _ => true,
+            })
+            .collect();
+
+        // propagate changes to importing modules until nothing changes
+        loop {
+            let mut changed = false;
+
+            for (idx, source) in sources.iter().enumerate() {
+                if outdated[idx] {
+                    continue;
+                }
+
+                let uses = &self.state.modules[&source.name].uses;
+                let is_outdated = |name: &String| {
+                    self.rebuilt.contains(name)
+                        || sources
+                            .iter()
+                            .zip(outdated.iter())
+                            .any(|(other, outdated)| *outdated && other.name == *name)
+                };
+
+                if uses.iter().any(is_outdated) {
+                    outdated[idx] = true;
+                    changed = true;
+                }
+            }
+
+            if !changed {
+                return outdated;
+            }
+        }
+    }
+
+    /// Directories searched for modules imported by a package. Like at runtime,
+    /// these are the directories in `LOL_PATH` and the search paths of the
+    /// package. The sources of dependencies are searched as well as they are
+    /// only compiled into the output directory.
+    fn import_dirs(&self, manifest: &Manifest) -> Result<Vec<PathBuf>, Diagnostic> {
+        let mut dirs = lol_path();
+        dirs.extend(manifest.search_dirs());
+        for dependency in manifest.dependencies.iter() {
+            let root = manifest.dependency_dir(dependency);
+            let package =
+                Manifest::from_dir(&root).map_err(|e| Diagnostic::from_manifest_error(&e))?;
+            dirs.extend(package.source_dirs());
+        }
+        Ok(dirs)
+    }
+
+    /// Compile all outdated modules of a package into the output directory.
+    fn build_package(&mut self, manifest: &Manifest) -> Result<Option<EntryPoint>, Diagnostic> {
+        let sources = self.find_sources(manifest)?;
+        let import_dirs = self.import_dirs(manifest)?;
+        let outdated = self.outdated(&sources);
+        self.sources
+            .extend(sources.iter().map(|source| source.name.clone()));
+        let mut entry_point = None;
+
+        for (source, outdated) in sources.into_iter().zip(outdated) {
+            let module = if outdated {
+                self.compile(&source, &import_dirs)?
+            } else {
+                Module::load_from_file(&source.target)
+                    .map_err(|e| Diagnostic::from_runtime_error(&e, None, None))?
+            };
+
+            let is_entry = match &manifest.entry {
+                Some(entry) => source.name == *entry,
+                _ => module.slot(&ENTRY_POINT.into()).is_some(),
+            };
+
+            if is_entry {
+                if module.slot(&ENTRY_POINT.into()).is_none() {
+                    return Err(Diagnostic {
+                        msg: format!(
+                            "entry module `{}` has no `{}` function",
+                            source.name, ENTRY_POINT
+                        ),
+                        loc: None,
+                        hint: None,
+                    });
+                }
+                entry_point = Some((source.path, source.target));
+            }
+        }
+
+        Ok(entry_point)
+    }
+
+    /// Forget modules whose source was removed and delete their output.
+    fn prune(&mut self) -> Result<(), Diagnostic> {
+        let sources = &self.sources;
+        let mut removed: Vec<String> = self
+            .state
+            .modules
+            .keys()
+            .filter(|name| !sources.contains(name))
+            .cloned()
+            .collect();
+        removed.sort();
+
+        for name in removed.into_iter() {
+            self.state.modules.remove(&name);
+
+            let mut target = self.outdir.join(module_path(&name));
+            target.set_extension(LOLC_EXTENSION);
+            match std::fs::remove_file(&target) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(io_error(&target, e))
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn compile(
+        &mut self,
+        source: &SourceFile,
+        import_dirs: &[PathBuf],
+    ) -> Result<Module, Diagnostic> {
+        // mirror the directory tree of nested modules
+        if let Some(parent) = source.target.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| io_error(parent, e))?;
+        }
+
+        let mut transpiler = Transpiler::new();
+        for dir in import_dirs.iter() {
+            transpiler.add_search_path(dir);
+        }
+        let module = transpiler
+            .build_from_path_as(&source.path, &source.name)
+            .map_err(|e| {
+                let content = std::fs::read_to_string(&source.path).ok();
+                Diagnostic::from_compile_error(&e, content.as_deref())
+            })?;
+        self.warnings.extend(transpiler.warnings().iter().cloned());
+
+        module
+            .store_to_file(&source.target)
+            .map_err(|e| Diagnostic::from_runtime_error(&e, None, None))?;
+
+        self.state.modules.insert(
+            source.name.clone(),
+            ModuleState {
+                hash: source.hash,
+                uses: module.uses().to_vec(),
+            },
+        );
+        self.rebuilt.push(source.name.clone());
+
+        Ok(module)
+    }
+}
@@ -0,0 +1,131 @@
+use lovm2::code::CodeObject;
+use lovm2::module::Module;
+use std::path::{Path, PathBuf};
+
+use crate::util::fnv1a;
+use crate::LOLC_EXTENSION;
+
+/// Environment variable overriding the directory of the compile cache.
+pub const LOL_CACHE_DIR: &str = "LOL_CACHE_DIR";
+/// Version of the cache layout. Has to be increased whenever the format of the
+/// entries changes. Changes of the generated code are covered by the crate
+/// version which is part of [cache_version].
+pub const CACHE_FORMAT_VERSION: u32 = 1;
+
+/// Extension of the files recording what a cached module was compiled from.
+const STAMP_EXTENSION: &str = "stamp";
+
+/// Stamp of the compiler and cache layout. Cached modules stored with another
+/// stamp are not used.
+pub fn cache_version() -> String {
+    format!("{}+{}", env!("CARGO_PKG_VERSION"), CACHE_FORMAT_VERSION)
+}
+
+/// Directory of the compile cache. Taken from `LOL_CACHE_DIR` and falls back to
+/// the cache directory of the user.
+pub fn default_cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os(LOL_CACHE_DIR) {
+        return Some(PathBuf::from(dir));
+    }
+
+    match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => Some(PathBuf::from(dir).join("lol")),
+        _ => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache").join("lol")),
+    }
+}
+
+fn hash_file(path: &Path) -> Option<u64> {
+    std::fs::read(path).ok().map(fnv1a)
+}
+
+/// Compiled modules of `.lol` files imported at runtime. An entry is only used
+/// if neither its source, the sources of imported macros nor the
+/// [cache_version] changed since it was stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileCache {
+    dir: PathBuf,
+}
+
+impl CompileCache {
+    pub fn new<T>(dir: T) -> Self
+    where
+        T: AsRef<Path>,
+    {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Every source file, module name and namespace separator gets an entry of
+    /// its own.
+    fn entry(&self, path: &Path, name: &str, separator: &str) -> PathBuf {
+        let path = path.display().to_string();
+        let key = path.bytes().chain([0]).chain(name.bytes());
+        let key = fnv1a(key.chain([0]).chain(separator.bytes()));
+        self.dir.join(format!("{:016x}", key))
+    }
+
+    /// Look up the compiled module for `path`. Returns `None` if there is no
+    /// entry or it is outdated.
+    pub fn load(&self, path: &Path, name: &str, separator: &str) -> Option<Module> {
+        let entry = self.entry(path, name, separator);
+        let stamp = std::fs::read_to_string(entry.with_extension(STAMP_EXTENSION)).ok()?;
+        let mut lines = stamp.lines();
+
+        if lines.next()? != cache_version() {
+            return None;
+        }
+
+        for line in lines {
+            let (hash, file) = line.split_once(' ')?;
+            let hash = u64::from_str_radix(hash, 16).ok()?;
+            if hash_file(Path::new(file))? != hash {
+                return None;
+            }
+        }
+
+        let mut co = CodeObject::load_from_file(entry.with_extension(LOLC_EXTENSION)).ok()?;
+        // lovm2 takes name and location from the file it was loaded from
+        co.name = name.to_string();
+        co.loc = Some(path.display().to_string());
+
+        Some(co.into())
+    }
+
+    /// Store the module compiled from `path`. `sources` are all files the
+    /// module depends on including `path` itself. Failures are ignored as the
+    /// module is simply compiled again next time.
+    pub fn store(
+        &self,
+        path: &Path,
+        name: &str,
+        separator: &str,
+        module: &Module,
+        sources: &[PathBuf],
+    ) {
+        let entry = self.entry(path, name, separator);
+        let mut stamp = cache_version();
+        for source in sources.iter() {
+            match hash_file(source) {
+                Some(hash) => stamp.push_str(&format!("\n{:016x} {}", hash, source.display())),
+                _ => return,
+            }
+        }
+
+        // an entry without stamp is never used, even if writing fails halfway
+        let _ = std::fs::remove_file(entry.with_extension(STAMP_EXTENSION));
+        if std::fs::create_dir_all(&self.dir).is_err() {
+            return;
+        }
+        if module
+            .store_to_file(entry.with_extension(LOLC_EXTENSION))
+            .is_ok()
+        {
+            let _ = std::fs::write(entry.with_extension(STAMP_EXTENSION), stamp);
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use lovm2::prelude::*;
+
+use crate::error::{CompileError, CompileWarning, Location};
+use crate::manifest::ManifestError;
+use crate::transpiler::{builtin_macros, TOPLEVEL_FORMS};
+
+/// Messages of conversion errors and the form that causes them. Conversions are
+/// compiled to instructions instead of calls, so they are not found as builtins.
+const CONVERSION_ERRORS: &[(&str, &str)] = &[("not an integer", "int"), ("not a float", "float")];
+
+/// A compiler or runtime error prepared for displaying it to the user.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub msg: String,
+    pub loc: Option<Location>,
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn from_compile_error(e: &CompileError, source: Option<&str>) -> Self {
+        let loc = e.location().cloned();
+
+        let hint = match e {
+            CompileError::UnexpectedToplevel { loc, .. } => source
+                .and_then(|source| form_name_at(source, loc))
+                .and_then(|name| suggest(&name, TOPLEVEL_FORMS.iter().copied())),
+            // a misspelled macro is compiled as a call, which makes its operands
+            // fail. therefore check the name of the surrounding form instead.
+            CompileError::UnexpectedForm { loc, .. } | CompileError::OperandCount { loc, .. } => {
+                source
+                    .and_then(|source| enclosing_form_name(source, loc))
+                    .and_then(|name| suggest(&name, builtin_macros()))
+            }
+            _ => None,
+        };
+
+        Self {
+            msg: e.message(),
+            loc,
+            hint,
+        }
+    }
+
+    pub fn from_compile_warning(w: &CompileWarning) -> Self {
+        Self {
+            msg: w.msg.clone(),
+            loc: Some(w.loc.clone()),
+            hint: None,
+        }
+    }
+
+    pub fn from_manifest_error(e: &ManifestError) -> Self {
+        Self {
+            msg: e.msg.clone(),
+            loc: e.loc.clone(),
+            hint: None,
+        }
+    }
+
+    /// Runtime errors do not carry a location. If the error was caused by a
+    /// failed function lookup, the first call site inside `source` is used.
+    /// [Interpreter::diagnose](crate::Interpreter::diagnose) locates errors of
+    /// builtin functions as well.
+    pub fn from_runtime_error(e: &Lovm2Error, file: Option<&str>, source: Option<&str>) -> Self {
+        let mut diagnostic = Self {
+            msg: match &e.ty {
+                Lovm2ErrorTy::LookupFailed => format!("cannot find `{}`", e.msg),
+                _ if e.msg.is_empty() => format!("{}", e.ty),
+                _ => format!("{}: {}", e.ty, e.msg),
+            },
+            loc: None,
+            hint: None,
+        };
+
+        if let (Lovm2ErrorTy::LookupFailed, Some(source)) = (&e.ty, source) {
+            diagnostic = diagnostic.at_call(&e.msg, file, Some(source));
+
+            let defined = defined_functions(source);
+            let candidates = builtin_macros().map(str::to_string).chain(defined);
+            diagnostic.hint = suggest(&e.msg, candidates);
+        }
+
+        if let Lovm2ErrorTy::Basic = &e.ty {
+            if let Some((_, form)) = CONVERSION_ERRORS.iter().find(|(msg, _)| *msg == e.msg) {
+                diagnostic = diagnostic.at_call(form, file, source);
+            }
+        }
+
+        diagnostic
+    }
+
+    /// Point to the first call of function `name` inside `source` unless the
+    /// location is known already.
+    pub fn at_call(mut self, name: &str, file: Option<&str>, source: Option<&str>) -> Self {
+        if let (None, Some(source)) = (&self.loc, source) {
+            if let Some(span) = find_call_site(source, name) {
+                let file = file.map(str::to_string);
+                self.loc = Some(Location::new(file, source, span));
+            }
+        }
+        self
+    }
+
+    /// Render the diagnostic including the affected source line. If `source` is
+    /// not given, it will be read from the file noted in the location.
+    pub fn render(&self, source: Option<&str>) -> String {
+        self.render_as("error", source)
+    }
+
+    /// Render the diagnostic like [Diagnostic::render] but as warning.
+    pub fn render_warning(&self, source: Option<&str>) -> String {
+        self.render_as("warning", source)
+    }
+
+    fn render_as(&self, severity: &str, source: Option<&str>) -> String {
+        let mut out = format!("{}: {}\n", severity, self.msg);
+
+        if let Some(loc) = &self.loc {
+            let loaded = match (source, &loc.file) {
+                (None, Some(file)) => std::fs::read_to_string(file).ok(),
+                _ => None,
+            };
+            let source = source.or(loaded.as_deref());
+
+            match &loc.file {
+                Some(file) => out.push_str(&format!(" --> {}:{}:{}\n", file, loc.line, loc.column)),
+                _ => out.push_str(&format!(" --> {}:{}\n", loc.line, loc.column)),
+            }
+
+            if let Some(line) = source.and_then(|source| source.lines().nth(loc.line - 1)) {
+                let lineno = loc.line.to_string();
+                let pad = " ".repeat(lineno.len());
+                let start = loc.column - 1;
+                let width = (loc.span.1.saturating_sub(loc.span.0))
+                    .min(line.chars().count().saturating_sub(start))
+                    .max(1);
+
+                out.push_str(&format!("{} |\n", pad));
+                out.push_str(&format!("{} | {}\n", lineno, line));
+                out.push_str(&format!(
+                    "{} | {}{}\n",
+                    pad,
+                    " ".repeat(start),
+                    "^".repeat(width)
+                ));
+            }
+        }
+
+        if let Some(hint) = &self.hint {
+            out.push_str(&format!(" = hint: {}\n", hint));
+        }
+
+        out
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.render(None))
+    }
+}
+
+/// Returns the name of the form starting at `loc` e.g. `deff` for `(deff ...)`.
+fn form_name_at(source: &str, loc: &Location) -> Option<String> {
+    let text = source.get(loc.span.0..loc.span.1)?.strip_prefix('(')?;
+    let name: String = text
+        .trim_start()
+        .chars()
+        .take_while(|c| !c.is_whitespace() && *c != '(' && *c != ')')
+        .collect();
+
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Returns the name of the innermost form that contains `loc` without starting at it.
+fn enclosing_form_name(source: &str, loc: &Location) -> Option<String> {
+    fn walk(sexp: &ess::Sexp, span: (usize, usize)) -> Option<String> {
+        if let ess::Sexp::List(list, own) = sexp {
+            if !(own.0 <= span.0 && span.1 <= own.1) || *own == span {
+                return None;
+            }
+
+            if let Some(name) = list.iter().find_map(|item| walk(item, span)) {
+                return Some(name);
+            }
+
+            if let Some(ess::Sexp::Sym(name, _)) = list.first() {
+                return Some(name.to_string());
+            }
+        }
+        None
+    }
+
+    let (sexprs, _) = ess::parser::parse(source);
+    sexprs.iter().find_map(|sexpr| walk(sexpr, loc.span))
+}
+
+/// Find the first occurrence of `(name ...)` inside the source. If the name is
+/// never called, the first usage as a symbol is returned instead.
+fn find_call_site(source: &str, name: &str) -> Option<(usize, usize)> {
+    let is_delimiter = |c: char| c.is_whitespace() || "()[]{}\";".contains(c);
+    let find = |pattern: &str, skip_opening: bool| {
+        let mut offset = 0;
+
+        while let Some(idx) = source[offset..].find(pattern) {
+            let begin = offset + idx;
+            let end = begin + pattern.len();
+            let delimited_before =
+                skip_opening || source[..begin].chars().next_back().is_none_or(is_delimiter);
+            let delimited_after = source[end..].chars().next().is_none_or(is_delimiter);
+
+            if delimited_before && delimited_after {
+                let begin = if skip_opening { begin + 1 } else { begin };
+                return Some((begin, end));
+            }
+
+            offset = end;
+        }
+
+        None
+    };
+
+    find(&format!("({}", name), true).or_else(|| find(name, false))
+}
+
+/// Collect the names of all functions declared via `def` or `defp`.
+fn defined_functions(source: &str) -> Vec<String> {
+    let (sexprs, _) = ess::parser::parse(source);
+    sexprs
+        .iter()
+        .filter_map(|sexpr| match sexpr {
+            ess::Sexp::List(list, _) if list.len() > 1 => match (&list[0], &list[1]) {
+                (ess::Sexp::Sym(def, _), ess::Sexp::Sym(name, _))
+                    if def == "def" || def == "defp" =>
+                {
+                    Some(name.to_string())
+                }
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+/// Propose the most similar candidate if it is close enough to `name`.
+fn suggest<T, I>(name: &str, candidates: I) -> Option<String>
+where
+    T: AsRef<str>,
+    I: Iterator<Item = T>,
+{
+    let threshold = name.chars().count() / 3;
+
+    candidates
+        .map(|candidate| (edit_distance(name, candidate.as_ref()), candidate))
+        .filter(|(distance, _)| 0 < *distance && *distance <= threshold)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| format!("did you mean `{}`?", candidate.as_ref()))
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { prev } else { prev + 1 };
+            prev = row[j + 1];
+            row[j + 1] = cost.min(row[j] + 1).min(prev + 1);
+        }
+    }
+
+    row[b.len()]
+}
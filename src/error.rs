@@ -0,0 +1,171 @@
+use ess::parser::ParseError;
+use ess::span::ByteSpan;
+use lovm2::prelude::*;
+
+/// Position of a s-expression inside its source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    /// Path of the source file if the code was not compiled from a string.
+    pub file: Option<String>,
+    /// Line number starting at 1.
+    pub line: usize,
+    /// Column number starting at 1.
+    pub column: usize,
+    /// Half-open range of bytes inside the source.
+    pub span: ByteSpan,
+}
+
+impl Location {
+    pub fn new(file: Option<String>, source: &str, span: ByteSpan) -> Self {
+        let mut begin = span.0.min(source.len());
+        while !source.is_char_boundary(begin) {
+            begin -= 1;
+        }
+        let before = &source[..begin];
+        let line = before.matches('\n').count() + 1;
+        let column = match before.rfind('\n') {
+            Some(idx) => before[idx + 1..].chars().count() + 1,
+            _ => before.chars().count() + 1,
+        };
+
+        Self {
+            file,
+            line,
+            column,
+            span,
+        }
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}:{}", file, self.line, self.column),
+            _ => write!(f, "{}:{}", self.line, self.column),
+        }
+    }
+}
+
+/// Everything that can go wrong while turning lol source into a module.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompileError {
+    /// The source file could not be read.
+    Io { file: String, msg: String },
+    /// The source is not a valid s-expression.
+    Parse { msg: String, loc: Location },
+    /// A different kind of form was expected e.g. a symbol instead of a list.
+    UnexpectedForm {
+        expected: &'static str,
+        got: String,
+        loc: Location,
+    },
+    /// The form is not allowed on the top-level of a module.
+    UnexpectedToplevel { got: String, loc: Location },
+    /// A builtin macro was used with the wrong amount of operands.
+    OperandCount {
+        name: String,
+        expected: String,
+        got: usize,
+        loc: Location,
+    },
+    /// A macro could not be defined or expanded.
+    Macro { msg: String, loc: Location },
+    /// A function was used that is excluded by `import` or private to its module.
+    Import { msg: String, loc: Location },
+    /// A global variable was assigned that is not declared or constant.
+    Global { msg: String, loc: Location },
+    /// A variable was assigned via `set!` that is not bound or captured by `fn`.
+    Unbound { msg: String, loc: Location },
+    /// lovm2 rejected the generated hir.
+    Lowering(String),
+}
+
+/// Code that compiles but probably does not do what was intended.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileWarning {
+    pub msg: String,
+    pub loc: Location,
+}
+
+impl CompileError {
+    /// Returns the location inside the source if the error is related to a form.
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Self::Parse { loc, .. }
+            | Self::UnexpectedForm { loc, .. }
+            | Self::UnexpectedToplevel { loc, .. }
+            | Self::OperandCount { loc, .. }
+            | Self::Macro { loc, .. }
+            | Self::Import { loc, .. }
+            | Self::Global { loc, .. }
+            | Self::Unbound { loc, .. } => Some(loc),
+            Self::Io { .. } | Self::Lowering(_) => None,
+        }
+    }
+
+    /// Returns the description without location information.
+    pub fn message(&self) -> String {
+        match self {
+            Self::Io { file, msg } => format!("cannot read `{}`: {}", file, msg),
+            Self::Parse { msg, .. } => msg.clone(),
+            Self::UnexpectedForm { expected, got, .. } => {
+                format!("expected {}, got {}", expected, got)
+            }
+            Self::UnexpectedToplevel { got, .. } => format!("unexpected {} at top-level", got),
+            Self::OperandCount {
+                name,
+                expected,
+                got,
+                ..
+            } => format!("`{}` expects {} operand(s), got {}", name, expected, got),
+            Self::Macro { msg, .. }
+            | Self::Import { msg, .. }
+            | Self::Global { msg, .. }
+            | Self::Unbound { msg, .. } => msg.clone(),
+            Self::Lowering(msg) => format!("cannot generate bytecode: {}", msg),
+        }
+    }
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.location() {
+            Some(loc) => write!(f, "{}: {}", loc, self.message()),
+            _ => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+impl From<CompileError> for Lovm2Error {
+    fn from(e: CompileError) -> Self {
+        (
+            Lovm2ErrorTy::Custom("CompileError".to_string()),
+            e.to_string(),
+        )
+            .into()
+    }
+}
+
+/// Walks down the chain of parser errors and returns the innermost cause
+/// together with the most precise span available.
+pub(crate) fn describe_parse_error(err: &ParseError) -> (String, ByteSpan) {
+    fn walk(err: &ParseError, context: &'static str, span: ByteSpan) -> (String, ByteSpan) {
+        match err {
+            ParseError::UnexpectedEof => (format!("unexpected end of input in {}", context), span),
+            ParseError::Unexpected(c, begin) => (
+                format!("unexpected character `{}` in {}", c, context),
+                (*begin, *begin + c.len_utf8()),
+            ),
+            ParseError::List(inner, loc) => walk(inner, "list", *loc),
+            ParseError::Sexp(inner, loc) => walk(inner, "expression", *loc),
+            ParseError::Char(inner, loc) => walk(inner, "character literal", *loc),
+            ParseError::String(inner, loc) => walk(inner, "string literal", *loc),
+            ParseError::Symbol(inner, loc) => walk(inner, "symbol", *loc),
+            ParseError::Number(inner, loc) => walk(inner, "number", *loc),
+        }
+    }
+
+    walk(err, "expression", (0, 0))
+}
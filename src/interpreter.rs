@@ -1,56 +1,376 @@
+use lovm2::code::CodeObject;
 use lovm2::module::Module;
 use lovm2::prelude::*;
-use lovm2::vm::{Context, Vm};
-use std::path::Path;
+use lovm2::vm::{find_module, Context, LoadRequest, Vm};
+use lovm2::Instruction;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
 
-use crate::transpiler::Transpiler;
+use crate::cache::{default_cache_dir, CompileCache};
+use crate::diagnostic::Diagnostic;
+use crate::error::CompileWarning;
+use crate::limits::{InterruptHandle, Limits};
+use crate::native::{native_function, NativeModule};
+use crate::runtime;
+use crate::sandbox::Sandbox;
+use crate::transpiler::{
+    is_generated, is_private, private_name, CompileResult, Transpiler, SCRIPT_ENTRY,
+};
+use crate::util::{
+    lol_path, module_candidates, namespaced, relative_module_candidates, replace_function,
+    source_root, NAMESPACE_SEPARATOR,
+};
 use crate::{LOLC_EXTENSION, LOL_EXTENSION};
 
-fn load_lol_module<T>(path: T) -> Result<Module, String>
-where
-    T: AsRef<Path>,
-{
-    match path.as_ref().extension() {
-        Some(ext) if ext == LOL_EXTENSION => {
-            let mut trans = Transpiler::new();
-            trans.build_from_path(path)
+/// Environment variable listing directories that are searched for imported
+/// modules. Separated like `PATH`.
+pub const LOL_PATH: &str = "LOL_PATH";
+
+/// Finds and compiles modules requested by `import`.
+#[derive(Clone, Debug, Default)]
+struct Loader {
+    /// Directories searched after the directory of the importing module.
+    search_paths: Vec<PathBuf>,
+    /// Directories lovm2 loads native modules from, searched last.
+    native_paths: Vec<String>,
+    cache: Option<CompileCache>,
+    /// Joins module and function names of imported modules.
+    separator: String,
+    /// Source roots of the loaded modules by location. Their imports are
+    /// searched up to it.
+    roots: RefCell<HashMap<String, PathBuf>>,
+}
+
+type LoadHook = Rc<dyn Fn(&LoadRequest) -> Lovm2Result<Option<Module>>>;
+
+impl Loader {
+    fn load_module<T>(&self, path: T, name: Option<&str>) -> Lovm2Result<Module>
+    where
+        T: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        if let Some(root) = name.and_then(|name| source_root(path.parent()?, name)) {
+            self.roots
+                .borrow_mut()
+                .insert(path.display().to_string(), root.to_path_buf());
         }
-        Some(ext) if ext == LOLC_EXTENSION => {
-            Module::load_from_file(path).map_err(|e| format!("{:?}", e))
+
+        match path.extension() {
+            Some(ext) if ext == LOL_EXTENSION => {
+                let name = match (name, path.file_stem()) {
+                    (Some(name), _) => name.to_string(),
+                    (_, Some(stem)) => stem.to_string_lossy().to_string(),
+                    _ => DEFAULT_MODULE_NAME.to_string(),
+                };
+
+                let sep = self.separator.as_str();
+                if let Some(module) = self.cache.as_ref().and_then(|c| c.load(path, &name, sep)) {
+                    return Ok(module);
+                }
+
+                let mut trans = Transpiler::new().separator(sep);
+                for dir in self.search_paths.iter() {
+                    trans.add_search_path(dir);
+                }
+                let module = trans.build_from_path_as(path, &name)?;
+
+                if let Some(cache) = &self.cache {
+                    let mut sources = vec![path.to_path_buf()];
+                    sources.extend(trans.macro_sources().iter().cloned());
+                    cache.store(path, &name, sep, &module, &sources);
+                }
+
+                Ok(module)
+            }
+            Some(ext) if ext == LOLC_EXTENSION => {
+                let mut co = CodeObject::load_from_file(path)?;
+                // lovm2 names modules after their file which is wrong for nested ones
+                if let Some(name) = name {
+                    co.name = name.to_string();
+                }
+                Ok(co.into())
+            }
+            _ => err_from_string("invalid file extension"),
         }
-        _ => Err("invalid file extension".to_string()),
     }
-}
 
-fn load_hook(req: &lovm2::vm::LoadRequest) -> Lovm2Result<Option<Module>> {
-    if let Ok(path) = lovm2::vm::find_candidate(req) {
-        let module = load_lol_module(path)?;
-        return Ok(Some(module));
+    fn load_hook(&self, req: &LoadRequest) -> Lovm2Result<Option<Module>> {
+        let extensions = [LOLC_EXTENSION, LOL_EXTENSION];
+        let mut candidates = vec![];
+
+        // modules next to the importing one take precedence
+        if let Some(location) = &req.relative_to {
+            let roots = self.roots.borrow();
+            let root = roots.get(location).map(PathBuf::as_path);
+            if let Some(dir) = Path::new(location).parent() {
+                candidates.extend(relative_module_candidates(
+                    &req.module,
+                    dir,
+                    root,
+                    &extensions,
+                ));
+            }
+        }
+
+        for dir in self.search_paths.iter() {
+            candidates.extend(module_candidates(&req.module, dir, &extensions));
+        }
+
+        if let Some(path) = candidates.iter().find(|path| path.is_file()) {
+            return self.load_module(path, Some(&req.module)).map(Some);
+        }
+
+        // shared objects are only found by lovm2 itself
+        if let Ok(path) = find_module(&req.module, &self.native_paths) {
+            return Ok(Some(Module::load_from_file(path)?));
+        }
+
+        let mut msg = format!("module `{}` not found. searched:", req.module);
+        for path in candidates.iter() {
+            msg.push_str(&format!("\n  {}", path.display()));
+        }
+        for dir in self.native_paths.iter() {
+            msg.push_str(&format!("\n  {} (native modules)", dir));
+        }
+        Err((Lovm2ErrorTy::ModuleNotFound, msg).into())
     }
-    Ok(None)
 }
 
-fn import_hook(module: Option<&str>, name: &str) -> Lovm2Result<Option<String>> {
+fn import_hook(separator: &str, module: Option<&str>, name: &str) -> Lovm2Result<Option<String>> {
+    // private functions are only called from inside their module which does
+    // not require a lookup
+    if is_private(name) {
+        return Ok(None);
+    }
+
+    // generated names are unique already and must not change as code refers to
+    // them by value. make sure they are only added once.
+    if is_generated(name) {
+        return Ok(module.map(|_| name.to_string()));
+    }
+
     let name = name.replace("_", "-");
     let name = match module {
-        Some(module) => format!("{}-{}", module, name),
-        _ => name.to_string(),
+        Some(module) => namespaced(module, &name, separator),
+        _ => name,
     };
     Ok(Some(name))
 }
 
 pub struct Interpreter {
     vm: Vm,
+    /// Compiles the sources passed to [Interpreter::eval]. Macros defined there
+    /// stay available for later calls.
+    trans: Transpiler,
+    evals: usize,
+    limits: Rc<Limits>,
+    /// Whether the checkpoints of generated code report to `limits`. This is
+    /// only enabled once a limit is used.
+    checkpoints: bool,
+    /// Functions defined via `defp` in the loaded modules as name used by
+    /// importers, module and function.
+    private: Vec<(String, String, String)>,
+    /// Names of the modules that passed [Interpreter::check_visibility].
+    checked: HashSet<String>,
+    loader: Rc<RefCell<Loader>>,
+    /// Loads imports like the vm does. Used to check them before they are added.
+    load_hook: LoadHook,
+    /// Imports that were checked but not added to the vm yet by name.
+    preloaded: Rc<RefCell<HashMap<String, Module>>>,
+    failed: runtime::FailedBuiltin,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Configures an [Interpreter] before it is created.
+///
+/// ``` rust
+/// use lol::Interpreter;
+///
+/// let int = Interpreter::builder()
+///     .sandboxed()
+///     .allow_module("util")
+///     .allow_function("print")
+///     .build();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct InterpreterBuilder {
+    sandbox: Option<Sandbox>,
+    separator: Option<String>,
+}
+
+impl InterpreterBuilder {
+    /// Deny builtin functions doing I/O and imports of modules from disk.
+    pub fn sandboxed(mut self) -> Self {
+        self.sandbox.get_or_insert_with(Sandbox::default);
+        self
+    }
+
+    /// Allow importing module `name` inside the sandbox.
+    pub fn allow_module(mut self, name: &str) -> Self {
+        let sandbox = self.sandbox.get_or_insert_with(Sandbox::default);
+        sandbox.modules.push(name.to_string());
+        self
+    }
+
+    /// Allow the builtin function `name` inside the sandbox.
+    pub fn allow_function(mut self, name: &str) -> Self {
+        let sandbox = self.sandbox.get_or_insert_with(Sandbox::default);
+        sandbox.functions.push(name.to_string());
+        self
+    }
+
+    /// Join module and function names of imports using `separator` e.g.
+    /// `regex::match` instead of `regex-match`. Precompiled modules have to be
+    /// built with the same separator.
+    pub fn separator(mut self, separator: &str) -> Self {
+        self.separator = Some(separator.to_string());
+        self
+    }
+
+    pub fn build(self) -> Interpreter {
+        let separator = self
+            .separator
+            .unwrap_or_else(|| NAMESPACE_SEPARATOR.to_string());
+        let failed = runtime::FailedBuiltin::default();
+        let mut vm = Vm::new();
+        vm.add_module(runtime::std_module(&failed), false)
+            .expect("builtins are added to an empty vm");
+
+        let loader = Rc::new(RefCell::new(Loader {
+            search_paths: lol_path(),
+            native_paths: vm.load_paths.clone(),
+            cache: default_cache_dir().map(CompileCache::new),
+            separator: separator.clone(),
+            roots: RefCell::default(),
+        }));
+        let hook_loader = loader.clone();
+        let hook = move |req: &LoadRequest| hook_loader.borrow().load_hook(req);
+
+        let load_hook: LoadHook = match self.sandbox {
+            Some(sandbox) => {
+                sandbox
+                    .install(&mut vm)
+                    .expect("denied functions can always be replaced");
+                Rc::new(sandbox.load_hook(hook))
+            }
+            _ => Rc::new(hook),
+        };
+        let preloaded = Rc::new(RefCell::new(HashMap::<String, Module>::new()));
+        let (hook_preloaded, hook) = (preloaded.clone(), load_hook.clone());
+        vm.set_load_hook(
+            move |req| match hook_preloaded.borrow_mut().remove(&req.module) {
+                Some(module) => Ok(Some(module)),
+                _ => hook(req),
+            },
+        );
+        let hook_separator = separator.clone();
+        vm.set_import_hook(move |module, name| import_hook(&hook_separator, module, name));
+        runtime::install(&mut vm).expect("runtime functions are only added once");
+
+        let mut trans = Transpiler::new().script_mode().separator(&separator);
+        for dir in loader.borrow().search_paths.iter() {
+            trans.add_search_path(dir);
+        }
+
+        Interpreter {
+            vm,
+            trans,
+            evals: 0,
+            limits: Rc::new(Limits::default()),
+            checkpoints: false,
+            private: vec![],
+            checked: HashSet::new(),
+            loader,
+            load_hook,
+            preloaded,
+            failed,
+        }
+    }
 }
 
 impl Interpreter {
     pub fn new() -> Self {
-        let mut vm = lovm2::create_vm_with_std();
+        Self::builder().build()
+    }
+
+    pub fn builder() -> InterpreterBuilder {
+        InterpreterBuilder::default()
+    }
+
+    /// Search imported modules inside `dir` as well. Directories are searched in
+    /// the order they were added, starting with those listed in `LOL_PATH`.
+    pub fn add_search_path<T>(&mut self, dir: T)
+    where
+        T: AsRef<Path>,
+    {
+        self.trans.add_search_path(&dir);
+        self.loader
+            .borrow_mut()
+            .search_paths
+            .push(dir.as_ref().to_path_buf());
+    }
+
+    /// Directories searched for imported modules.
+    pub fn search_paths(&self) -> Vec<PathBuf> {
+        self.loader.borrow().search_paths.clone()
+    }
+
+    /// Cache compiled `.lol` modules inside `dir`. The cache is located in
+    /// `LOL_CACHE_DIR` or the cache directory of the user by default. `None`
+    /// disables caching.
+    pub fn set_cache_dir<T>(&mut self, dir: Option<T>)
+    where
+        T: AsRef<Path>,
+    {
+        self.loader.borrow_mut().cache = dir.map(CompileCache::new);
+    }
+
+    pub fn cache_dir(&self) -> Option<PathBuf> {
+        let loader = self.loader.borrow();
+        loader.cache.as_ref().map(|cache| cache.dir().to_path_buf())
+    }
+
+    /// Count the checkpoints of generated code. Without limits they are skipped.
+    fn enable_checkpoints(&mut self) {
+        if self.checkpoints {
+            return;
+        }
+        let limits = self.limits.clone();
+        self.vm
+            .set_interrupt(runtime::CHECKPOINT, move |_| limits.step())
+            .expect("checkpoint interrupt is not reserved");
+        self.checkpoints = true;
+    }
+
+    /// Abort `run` and `call` after `steps` function calls and loop iterations.
+    pub fn set_step_limit(&mut self, steps: Option<u64>) {
+        if steps.is_some() {
+            self.enable_checkpoints();
+        }
+        self.limits.step_limit.set(steps);
+    }
 
-        vm.set_load_hook(load_hook);
-        vm.set_import_hook(import_hook);
+    /// Abort `run` and `call` if they take longer than `timeout`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        if timeout.is_some() {
+            self.enable_checkpoints();
+        }
+        self.limits.timeout.set(timeout);
+    }
 
-        Self { vm }
+    /// Handle for aborting a running `run` or `call` from another thread.
+    /// Interrupts issued while nothing is running are ignored.
+    pub fn interrupt_handle(&mut self) -> InterruptHandle {
+        self.enable_checkpoints();
+        self.limits.handle.clone()
     }
 
     pub fn context_mut(&mut self) -> &mut Context {
@@ -62,24 +382,221 @@ impl Interpreter {
         T: Into<Value> + Clone,
     {
         let args: Vec<Value> = args.iter().map(T::clone).map(T::into).collect();
-        self.vm.call(name, args.as_ref())
+
+        // a failing call leaves its frames and values behind
+        let lstack_len = self.vm.context_mut().lstack_mut().len();
+        let vstack_len = self.vm.context_mut().stack_mut().len();
+
+        self.limits.start();
+        *self.failed.borrow_mut() = None;
+        let result = self.vm.call(name, args.as_ref());
+
+        if result.is_err() {
+            self.vm.context_mut().lstack_mut().truncate(lstack_len);
+            self.vm.context_mut().stack_mut().truncate(vstack_len);
+        }
+
+        result
+    }
+
+    /// Compile `source` into a new anonymous module and run it. Top-level forms
+    /// and expressions can be mixed. Definitions replace functions of the same
+    /// name. Returns the value of the last expression.
+    pub fn eval(&mut self, source: &str) -> Lovm2Result<Value> {
+        let module = self.compile(source)?;
+        self.eval_module(module)
+    }
+
+    /// Prepare an error of [Interpreter::run], [Interpreter::call] or
+    /// [Interpreter::eval] for displaying it. If a builtin function failed, its
+    /// first call inside `source` is used as location.
+    pub fn diagnose(&self, e: &Lovm2Error, file: Option<&str>, source: Option<&str>) -> Diagnostic {
+        let diagnostic = Diagnostic::from_runtime_error(e, file, source);
+        match &*self.failed.borrow() {
+            Some((name, ty, msg)) if *ty == e.ty.to_string() && *msg == e.msg => {
+                diagnostic.at_call(name, file, source)
+            }
+            _ => diagnostic,
+        }
+    }
+
+    /// Compile `source` like [Interpreter::eval] without running it. Macros,
+    /// imports and variables of earlier evaluations are known.
+    pub fn compile(&mut self, source: &str) -> CompileResult<Module> {
+        self.evals += 1;
+        let name = format!("{{eval-{}}}", self.evals);
+        self.trans.build(name.into(), source)
+    }
+
+    /// Like [Interpreter::compile] but reads the source from `path`. Imports
+    /// are searched relative to it.
+    pub fn compile_file<T>(&mut self, path: T) -> CompileResult<Module>
+    where
+        T: AsRef<Path>,
+    {
+        self.trans.build_from_path(path)
+    }
+
+    /// Warnings of the last [Interpreter::compile] or [Interpreter::compile_file].
+    pub fn warnings(&self) -> &[CompileWarning] {
+        self.trans.warnings()
+    }
+
+    /// Load a module compiled by [Interpreter::compile] and run its top-level
+    /// expressions.
+    pub fn eval_module(&mut self, module: Module) -> Lovm2Result<Value> {
+        self.load_replacing(module)?;
+        self.call::<Value>(SCRIPT_ENTRY, &[])
+    }
+
+    /// Reject modules calling functions that other modules define via `defp`.
+    /// Checks `module` and the modules it imports before any of them is added to
+    /// the vm. Modules compiled from sources are checked by the transpiler
+    /// already, but precompiled ones and imports found at runtime are not.
+    fn check_visibility(&mut self, module: &Module) -> Lovm2Result<()> {
+        let mut modules = vec![module.clone()];
+        let mut idx = 0;
+        while idx < modules.len() {
+            let importer = modules[idx].clone();
+            for used_module in importer.uses() {
+                let known = modules.iter().any(|m| m.name() == used_module);
+                if known || self.checked.contains(used_module) {
+                    continue;
+                }
+                let req = LoadRequest {
+                    module: used_module.clone(),
+                    relative_to: importer.location().cloned(),
+                };
+                if let Some(module) = (self.load_hook)(&req)? {
+                    modules.push(module);
+                }
+            }
+            idx += 1;
+        }
+
+        let separator = self.loader.borrow().separator.clone();
+        let mut private = vec![];
+        for module in modules.iter() {
+            if self.checked.contains(module.name()) {
+                continue;
+            }
+            for (key, _) in module.slots().iter() {
+                if let Some(function) = private_name(key.as_ref()) {
+                    let function = function.replace("_", "-");
+                    let name = namespaced(module.name(), &function, &separator);
+                    private.push((name, module.name().to_string(), function));
+                }
+            }
+        }
+
+        for module in modules.iter() {
+            let co = &module.code_object;
+            for inx in co.code.iter() {
+                let ident = match inx {
+                    Instruction::Call(idx, _) | Instruction::LCall(idx, _) => {
+                        &co.idents[*idx as usize]
+                    }
+                    _ => continue,
+                };
+                let private = self
+                    .private
+                    .iter()
+                    .chain(private.iter())
+                    .find(|(name, owner, _)| name == ident.as_ref() && owner != module.name());
+                if let Some((_, owner, function)) = private {
+                    return err_from_string(format!(
+                        "module `{}` calls function `{}` of module `{}` which is private",
+                        module.name(),
+                        function,
+                        owner
+                    ));
+                }
+            }
+        }
+
+        self.private.extend(private);
+        let mut preloaded = self.preloaded.borrow_mut();
+        for (idx, module) in modules.into_iter().enumerate() {
+            self.checked.insert(module.name().to_string());
+            // the vm loads imports through its hook which returns them from here
+            if idx > 0 {
+                preloaded.insert(module.name().to_string(), module);
+            }
+        }
+
+        Ok(())
     }
 
     pub fn load(&mut self, module: Module) -> Lovm2Result<()> {
         // import module namespaced
+        self.check_visibility(&module)?;
         self.vm.add_module(module, true)
     }
 
     pub fn load_global(&mut self, module: Module) -> Lovm2Result<()> {
         // import module namespaced
+        self.check_visibility(&module)?;
         self.vm.add_module(module, false)
     }
 
+    /// Import module globally. Other than [Interpreter::load_global], functions
+    /// that are already defined will be replaced. This is useful for interactive
+    /// sessions.
+    pub fn load_replacing(&mut self, module: Module) -> Lovm2Result<()> {
+        self.check_visibility(&module)?;
+        for used_module in module.uses() {
+            self.vm
+                .add_module_by_name(used_module, module.location().cloned(), true)?;
+        }
+
+        let separator = self.loader.borrow().separator.clone();
+        for (key, co) in module.slots().iter() {
+            let name = match import_hook(&separator, None, key.as_ref())? {
+                None => import_hook(&separator, Some(module.name()), key.as_ref())?,
+                name => name,
+            };
+
+            if let Some(name) = name {
+                replace_function(&mut self.vm, name, co.clone())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Make a Rust function callable from lol code as `name`. Fails if a
+    /// function with this name already exists.
+    pub fn register<F>(&mut self, name: &str, f: F) -> Lovm2Result<()>
+    where
+        F: Fn(&[Value]) -> Lovm2Result<Value> + 'static,
+    {
+        // the vm replaces existing functions before reporting the conflict
+        if self
+            .vm
+            .context_mut()
+            .lookup_code_object(&name.into())
+            .is_ok()
+        {
+            return Err((Lovm2ErrorTy::ImportConflict, name).into());
+        }
+        self.vm.add_function(name, native_function(f))
+    }
+
+    /// Load the functions of a [NativeModule]. They are namespaced like those of
+    /// imported lol modules e.g. `geo-area` and `(import geo)` does not try to
+    /// load the module from disk anymore.
+    pub fn register_module(&mut self, module: NativeModule) -> Lovm2Result<()> {
+        self.load(module.into())
+    }
+
     pub fn load_main(&mut self, module: Module) -> Lovm2Result<()> {
+        self.check_visibility(&module)?;
         self.vm.add_main_module(module)
     }
 
     pub fn run(&mut self) -> Lovm2Result<Value> {
+        self.limits.start();
+        *self.failed.borrow_mut() = None;
         self.vm.run()
     }
 
@@ -87,10 +604,9 @@ impl Interpreter {
     where
         T: AsRef<Path>,
     {
-        let module = load_lol_module(path)?;
-
-        self.vm.add_main_module(module)?;
+        let module = self.loader.borrow().load_module(path, None)?;
+        self.load_main(module)?;
 
-        self.vm.run()
+        self.run()
     }
 }
@@ -1,8 +1,24 @@
+pub mod build;
+pub mod cache;
+pub mod diagnostic;
+pub mod error;
 pub mod interpreter;
+pub mod limits;
+pub mod manifest;
+pub mod native;
+pub mod repl;
+pub mod runtime;
+pub mod sandbox;
 pub mod transpiler;
 pub mod util;
 
-pub use crate::interpreter::Interpreter;
+pub use crate::diagnostic::Diagnostic;
+pub use crate::error::{CompileError, CompileWarning, Location};
+pub use crate::interpreter::{Interpreter, InterpreterBuilder};
+pub use crate::limits::InterruptHandle;
+pub use crate::manifest::Manifest;
+pub use crate::native::NativeModule;
+pub use crate::repl::Repl;
 pub use crate::transpiler::Transpiler;
 pub use crate::util::{create_lol_module, create_lol_runtime};
 
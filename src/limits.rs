@@ -0,0 +1,94 @@
+use lovm2::prelude::*;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Error type if a run exceeded its step limit.
+pub const STEP_LIMIT_EXCEEDED: &str = "StepLimitExceeded";
+/// Error type if a run took longer than its timeout.
+pub const TIMEOUT: &str = "Timeout";
+/// Error type if a run was aborted through an [InterruptHandle].
+pub const INTERRUPTED: &str = "Interrupted";
+
+/// Check if the error was caused by an execution limit. Such errors cannot be
+/// caught by scripts.
+pub fn is_limit_error(e: &Lovm2Error) -> bool {
+    match &e.ty {
+        Lovm2ErrorTy::Custom(ty) => {
+            matches!(ty.as_str(), STEP_LIMIT_EXCEEDED | TIMEOUT | INTERRUPTED)
+        }
+        _ => false,
+    }
+}
+
+/// Aborts the script currently running on an [Interpreter](crate::Interpreter).
+/// The handle can be sent to other threads.
+#[derive(Clone, Debug, Default)]
+pub struct InterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    fn is_interrupted(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    fn reset(&self) {
+        self.flag.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Bounds for a single `run` or `call`. Generated code reports a step on every
+/// function call and loop iteration.
+#[derive(Debug, Default)]
+pub(crate) struct Limits {
+    pub step_limit: Cell<Option<u64>>,
+    pub timeout: Cell<Option<Duration>>,
+    pub handle: InterruptHandle,
+    steps: Cell<u64>,
+    deadline: Cell<Option<Instant>>,
+}
+
+impl Limits {
+    /// Reset the counters before a new run.
+    pub fn start(&self) {
+        self.steps.set(0);
+        self.deadline
+            .set(self.timeout.get().map(|timeout| Instant::now() + timeout));
+        self.handle.reset();
+    }
+
+    /// Account for one step and fail if any limit was exceeded.
+    pub fn step(&self) -> Lovm2Result<()> {
+        if self.handle.is_interrupted() {
+            return Err((
+                Lovm2ErrorTy::Custom(INTERRUPTED.to_string()),
+                "execution was interrupted",
+            )
+                .into());
+        }
+
+        self.steps.set(self.steps.get() + 1);
+        if let Some(limit) = self.step_limit.get() {
+            if self.steps.get() > limit {
+                let msg = format!("step limit of {} exceeded", limit);
+                return Err((Lovm2ErrorTy::Custom(STEP_LIMIT_EXCEEDED.to_string()), msg).into());
+            }
+        }
+
+        if let Some(deadline) = self.deadline.get() {
+            if Instant::now() >= deadline {
+                let timeout = self.timeout.get().unwrap_or_default();
+                let msg = format!("timeout of {:?} exceeded", timeout);
+                return Err((Lovm2ErrorTy::Custom(TIMEOUT.to_string()), msg).into());
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::error::Location;
+
+/// Name of the manifest file inside a project directory.
+pub const MANIFEST_FILE: &str = "lol.toml";
+/// Source directory of projects without a manifest.
+pub const DEFAULT_SOURCE_DIR: &str = "src";
+/// Output directory of projects without a manifest.
+pub const DEFAULT_OUTPUT_DIR: &str = "target/lol";
+
+/// Project settings read from `lol.toml`.
+///
+/// ``` toml
+/// [package]
+/// name = "server"
+/// version = "0.1.0"
+/// entry = "main"
+///
+/// [build]
+/// sources = ["src"]
+/// output = "target/lol"
+/// search-paths = ["../shared"]
+///
+/// [dependencies]
+/// util = { path = "../util" }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Manifest {
+    /// Directory containing the manifest. Relative paths are resolved against it.
+    pub root: PathBuf,
+    pub name: String,
+    pub version: String,
+    /// Module containing the `main` function. If not set, all modules are
+    /// searched for it.
+    pub entry: Option<String>,
+    pub sources: Vec<PathBuf>,
+    pub output: PathBuf,
+    /// Directories searched for imported modules when running the project.
+    pub search_paths: Vec<PathBuf>,
+    pub dependencies: Vec<Dependency>,
+}
+
+/// Another lol package on the local filesystem.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dependency {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// The manifest could not be read or contains invalid settings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestError {
+    pub msg: String,
+    pub loc: Option<Location>,
+}
+
+impl std::fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.loc {
+            Some(loc) => write!(f, "{}: {}", loc, self.msg),
+            _ => write!(f, "{}", self.msg),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+impl Manifest {
+    /// Layout of projects without a manifest.
+    pub fn default_for<T>(root: T) -> Self
+    where
+        T: AsRef<Path>,
+    {
+        // paths like `.` have no file name before they are resolved
+        let name = root
+            .as_ref()
+            .canonicalize()
+            .unwrap_or_else(|_| root.as_ref().to_path_buf())
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        Self {
+            root: root.as_ref().to_path_buf(),
+            name,
+            version: String::new(),
+            entry: None,
+            sources: vec![PathBuf::from(DEFAULT_SOURCE_DIR)],
+            output: PathBuf::from(DEFAULT_OUTPUT_DIR),
+            search_paths: vec![],
+            dependencies: vec![],
+        }
+    }
+
+    /// Read `lol.toml` inside `root`. Directories without a manifest get the
+    /// default layout.
+    pub fn from_dir<T>(root: T) -> Result<Self, ManifestError>
+    where
+        T: AsRef<Path>,
+    {
+        let path = root.as_ref().join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(Self::default_for(root));
+        }
+
+        let source = std::fs::read_to_string(&path).map_err(|e| ManifestError {
+            msg: format!("cannot read `{}`: {}", path.display(), e),
+            loc: None,
+        })?;
+
+        Self::parse(root, &source, Some(path.display().to_string()))
+    }
+
+    /// Parse the content of a manifest. `file` is only used for error locations.
+    pub fn parse<T>(root: T, source: &str, file: Option<String>) -> Result<Self, ManifestError>
+    where
+        T: AsRef<Path>,
+    {
+        let parsed: ManifestFile = toml::from_str(source).map_err(|e| {
+            let msg = e.to_string();
+            let loc = e.line_col().map(|(line, column)| {
+                let begin = source
+                    .split_inclusive('\n')
+                    .take(line)
+                    .map(str::len)
+                    .sum::<usize>()
+                    + column;
+                Location::new(file.clone(), source, (begin, begin))
+            });
+            ManifestError {
+                // the location is reported separately
+                msg: match (&loc, msg.rsplit_once(" at line ")) {
+                    (Some(_), Some((msg, _))) => msg.to_string(),
+                    _ => msg,
+                },
+                loc,
+            }
+        })?;
+
+        let mut manifest = Self::default_for(root);
+        manifest.name = parsed.package.name;
+        manifest.version = parsed.package.version;
+        manifest.entry = parsed.package.entry;
+        if let Some(sources) = parsed.build.sources {
+            manifest.sources = sources;
+        }
+        if let Some(output) = parsed.build.output {
+            manifest.output = output;
+        }
+        manifest.search_paths = parsed.build.search_paths;
+        manifest.dependencies = parsed
+            .dependencies
+            .into_iter()
+            .map(|(name, dependency)| Dependency {
+                name,
+                path: match dependency {
+                    DependencySpec::Path(path) => path,
+                    DependencySpec::Table(table) => table.path,
+                },
+            })
+            .collect();
+
+        Ok(manifest)
+    }
+
+    /// Absolute paths of all source directories.
+    pub fn source_dirs(&self) -> Vec<PathBuf> {
+        self.sources.iter().map(|dir| self.root.join(dir)).collect()
+    }
+
+    /// Absolute paths of all directories searched for imported modules.
+    pub fn search_dirs(&self) -> Vec<PathBuf> {
+        self.search_paths
+            .iter()
+            .map(|dir| self.root.join(dir))
+            .collect()
+    }
+
+    /// Absolute path of the output directory.
+    pub fn output_dir(&self) -> PathBuf {
+        self.root.join(&self.output)
+    }
+
+    /// Absolute path of a dependency.
+    pub fn dependency_dir(&self, dependency: &Dependency) -> PathBuf {
+        self.root.join(&dependency.path)
+    }
+}
+
+/// Layout of `lol.toml` as read from disk.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestFile {
+    package: PackageTable,
+    #[serde(default)]
+    build: BuildTable,
+    #[serde(default)]
+    dependencies: BTreeMap<String, DependencySpec>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PackageTable {
+    name: String,
+    version: String,
+    entry: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct BuildTable {
+    sources: Option<Vec<PathBuf>>,
+    output: Option<PathBuf>,
+    #[serde(default)]
+    search_paths: Vec<PathBuf>,
+}
+
+/// A dependency is given as path or as `{ path = "..." }`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DependencySpec {
+    Path(PathBuf),
+    Table(DependencyTable),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DependencyTable {
+    path: PathBuf,
+}
@@ -0,0 +1,71 @@
+use lovm2::code::{CallableRef, CodeObject};
+use lovm2::extend::create_callable;
+use lovm2::module::{Module, Slots};
+use lovm2::prelude::*;
+
+use crate::runtime::pop_args;
+
+/// Wrap a Rust function so it can be called from lol code. The function
+/// receives all arguments of the call and its result is returned to the caller.
+pub fn native_function<F>(f: F) -> CallableRef
+where
+    F: Fn(&[Value]) -> Lovm2Result<Value> + 'static,
+{
+    create_callable(move |vm| {
+        let args = pop_args(vm)?;
+        let val = f(&args)?;
+        vm.context_mut().push_value(val);
+        Ok(())
+    })
+}
+
+/// A collection of Rust functions that can be loaded into an
+/// [Interpreter](crate::Interpreter) like a lol module.
+///
+/// ``` rust
+/// use lol::NativeModule;
+/// use lovm2::prelude::*;
+///
+/// let geo = NativeModule::new("geo").with("area", |args: &[Value]| {
+///     Ok(Value::from(args[0].as_integer_inner()? * args[1].as_integer_inner()?))
+/// });
+/// ```
+pub struct NativeModule {
+    name: String,
+    slots: Slots,
+}
+
+impl NativeModule {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            slots: Slots::new(),
+        }
+    }
+
+    /// Add a function to the module. Functions that were added before under
+    /// the same name are replaced.
+    pub fn with<F>(mut self, name: &str, f: F) -> Self
+    where
+        F: Fn(&[Value]) -> Lovm2Result<Value> + 'static,
+    {
+        self.slots.insert(name, native_function(f));
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl From<NativeModule> for Module {
+    fn from(native: NativeModule) -> Self {
+        let mut code_object = CodeObject::new();
+        code_object.name = native.name;
+
+        Module {
+            code_object: code_object.into(),
+            slots: native.slots,
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use lovm2::prelude::*;
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use std::path::Path;
+
+use crate::diagnostic::Diagnostic;
+use crate::interpreter::Interpreter;
+use crate::transpiler::is_generated;
+
+const PROMPT: &str = "lol> ";
+const PROMPT_CONTINUE: &str = "...  ";
+const HISTORY_FILE: &str = ".lol_history";
+
+/// Interactive session that keeps an [Interpreter] alive between inputs.
+pub struct Repl {
+    int: Interpreter,
+    functions: Vec<String>,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Self {
+            int: Interpreter::new(),
+            functions: vec![],
+        }
+    }
+
+    /// Functions defined in the current session.
+    pub fn functions(&self) -> &[String] {
+        &self.functions
+    }
+
+    /// Drop all definitions by starting over with a fresh interpreter.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Compile `source` and run its top-level expressions. Definitions are
+    /// added to the session and replace functions of the same name.
+    pub fn eval(&mut self, source: &str) -> Result<Value, Diagnostic> {
+        self.eval_source(source, None)
+    }
+
+    /// Evaluate the content of a file like [Repl::eval].
+    pub fn load<T>(&mut self, path: T) -> Result<Value, Diagnostic>
+    where
+        T: AsRef<Path>,
+    {
+        let source = std::fs::read_to_string(path.as_ref()).map_err(|e| Diagnostic {
+            msg: format!("cannot read `{}`: {}", path.as_ref().display(), e),
+            loc: None,
+            hint: None,
+        })?;
+        self.eval_source(&source, Some(path.as_ref().display().to_string()))
+    }
+
+    fn eval_source(&mut self, source: &str, file: Option<String>) -> Result<Value, Diagnostic> {
+        let module = match &file {
+            Some(file) => self.int.compile_file(file),
+            _ => self.int.compile(source),
+        }
+        .map_err(|e| Diagnostic::from_compile_error(&e, Some(source)))?;
+        for warning in self.int.warnings() {
+            eprint!(
+                "{}",
+                Diagnostic::from_compile_warning(warning).render_warning(Some(source))
+            );
+        }
+
+        let mut defined: Vec<String> = module
+            .slots()
+            .iter()
+            .map(|(key, _)| key.to_string())
+            .filter(|name| !is_generated(name))
+            .collect();
+        defined.sort();
+
+        let result = self.int.eval_module(module);
+
+        // functions stay defined even if the top-level expressions fail
+        for name in defined.into_iter() {
+            let loaded = self
+                .int
+                .context_mut()
+                .lookup_code_object(&name.as_str().into())
+                .is_ok();
+            if loaded && !self.functions.contains(&name) {
+                self.functions.push(name);
+            }
+        }
+
+        result.map_err(|e| self.int.diagnose(&e, file.as_deref(), Some(source)))
+    }
+
+    /// Handle a line starting with `:`. Returns `false` if the session should end.
+    fn command(&mut self, line: &str) -> bool {
+        let mut parts = line.splitn(2, char::is_whitespace);
+        let name = parts.next().unwrap_or_default();
+        let arg = parts.next().map(str::trim).unwrap_or_default();
+
+        match name {
+            ":funcs" => {
+                for name in self.functions.iter() {
+                    println!("{}", name);
+                }
+            }
+            ":help" => {
+                println!(":funcs        list functions defined in this session");
+                println!(":load <file>  evaluate a source file");
+                println!(":reset        forget all definitions");
+                println!(":quit         leave the repl");
+            }
+            ":load" if !arg.is_empty() => {
+                let source = std::fs::read_to_string(arg).ok();
+                match self.load(arg) {
+                    Ok(val) => print_value(&val),
+                    Err(e) => eprint!("{}", e.render(source.as_deref())),
+                }
+            }
+            ":quit" | ":q" => return false,
+            ":reset" => self.reset(),
+            _ => eprintln!("unknown command `{}`. see `:help`", line),
+        }
+
+        true
+    }
+
+    /// Run the read-eval-print loop on stdin until EOF.
+    pub fn run(&mut self) {
+        let mut editor = Editor::<()>::new();
+        let history = std::env::var("HOME")
+            .map(|home| Path::new(&home).join(HISTORY_FILE))
+            .ok();
+
+        if let Some(history) = &history {
+            let _ = editor.load_history(history);
+        }
+
+        let mut input = String::new();
+
+        loop {
+            let prompt = if input.is_empty() {
+                PROMPT
+            } else {
+                PROMPT_CONTINUE
+            };
+
+            match editor.readline(prompt) {
+                Ok(line) => {
+                    if input.is_empty() && line.trim().starts_with(':') {
+                        editor.add_history_entry(line.trim());
+                        if !self.command(line.trim()) {
+                            break;
+                        }
+                        continue;
+                    }
+
+                    input.push_str(&line);
+                    input.push('\n');
+
+                    if !is_complete(&input) {
+                        continue;
+                    }
+
+                    let source = std::mem::take(&mut input);
+                    if source.trim().is_empty() {
+                        continue;
+                    }
+
+                    editor.add_history_entry(source.trim());
+
+                    match self.eval(&source) {
+                        Ok(val) => print_value(&val),
+                        Err(e) => eprint!("{}", e.render(Some(&source))),
+                    }
+                }
+                // discard the current input on ctrl-c
+                Err(ReadlineError::Interrupted) => input.clear(),
+                Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    break;
+                }
+            }
+        }
+
+        if let Some(history) = &history {
+            let _ = editor.save_history(history);
+        }
+    }
+}
+
+fn print_value(val: &Value) {
+    if !matches!(val, Value::Nil) {
+        println!("{}", val);
+    }
+}
+
+/// Check if all parentheses inside `input` are closed. Strings and comments
+/// are skipped.
+pub fn is_complete(input: &str) -> bool {
+    let mut depth = 0;
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            '"' => loop {
+                match chars.next() {
+                    Some('\\') => {
+                        chars.next();
+                    }
+                    Some('"') => break,
+                    Some(_) => {}
+                    None => return false,
+                }
+            },
+            ';' => {
+                chars.any(|c| c == '\n');
+            }
+            // character literals like `#\(`
+            '#' if chars.as_str().starts_with('\\') => {
+                chars.nth(1);
+            }
+            _ => {}
+        }
+    }
+
+    depth <= 0
+}
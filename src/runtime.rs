@@ -0,0 +1,456 @@
+use lovm2::code::CallableRef;
+use lovm2::extend::create_callable;
+use lovm2::module::Module;
+use lovm2::prelude::*;
+use lovm2::value::{box_value, Reference};
+use lovm2::vm::Vm;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::limits::is_limit_error;
+use crate::util::replace_function;
+
+/// Call the function or closure passed as first argument with the remaining arguments.
+pub const CALL: &str = "{call}";
+/// Bind captured values to a lifted `fn`. The closure is a list of the function
+/// name followed by the captured values.
+pub const CLOSURE: &str = "{closure}";
+
+/// Run the blocks lifted from `try`. Expects the names of the body, handler and
+/// cleanup function, the variable for the caught error and the local variables
+/// shared with the blocks.
+pub const TRY: &str = "{try}";
+/// Abort with an error created from the argument.
+pub const RAISE: &str = "{raise}";
+/// Run the function assigning the globals of a module whose name is passed as
+/// argument. It only runs on the first call.
+pub const INIT: &str = "{init}";
+/// Assign the arguments of the calling function to its parameters. Expects the
+/// name of the function, the number of required and optional parameters,
+/// whether it takes the remaining arguments and the names of the parameters.
+/// Returns the values of all parameters as list, followed by a list telling for
+/// each optional parameter whether its argument is missing.
+pub const BIND: &str = "{bind}";
+/// Take the value at the index passed as second argument from the list returned
+/// by [BIND]. Other than indexing, this keeps lists and dicts passed as arguments
+/// shared with the caller.
+pub const ARGUMENT: &str = "{argument}";
+/// Wrap keyword arguments given as alternating names and values. They are
+/// passed as last argument of a call.
+pub const KEYWORDS: &str = "{keywords}";
+/// Type of errors raised with a value that does not specify one.
+pub const RAISED_ERROR: &str = "Error";
+/// Type of errors raised if the arguments of a call do not match the
+/// parameters of the function.
+pub const ARGUMENT_ERROR: &str = "ArgumentError";
+
+/// Builtin functions expecting the name of a function and the position of that
+/// argument. Closures passed to them are bound to a name for the duration of the call.
+const CALLBACK_FUNCTIONS: &[(&str, usize)] =
+    &[("call", 0), ("filter", 1), ("map", 1), ("serve", 1)];
+
+/// Interrupt triggered by generated code on every function call and loop
+/// iteration. The interpreter uses it to enforce execution limits.
+pub const CHECKPOINT: u16 = 64;
+
+/// Register the native functions generated code relies on.
+pub fn install(vm: &mut Vm) -> Lovm2Result<()> {
+    vm.add_function(CALL, create_callable(call))?;
+    vm.add_function(TRY, create_callable(run_try))?;
+    vm.add_function(RAISE, create_callable(raise))?;
+    vm.add_function(INIT, create_callable(init))?;
+    vm.add_function(BIND, create_callable(bind))?;
+    vm.add_function(ARGUMENT, create_callable(argument))?;
+    vm.add_function(KEYWORDS, create_callable(keywords))?;
+    vm.add_function(CLOSURE, create_callable(closure))?;
+
+    let names = Rc::new(RefCell::new(ClosureNames::default()));
+    for (name, position) in CALLBACK_FUNCTIONS.iter() {
+        // builtins are reachable with and without their module name
+        for key in [name.to_string(), format!("std.{}", name)] {
+            let builtin = match vm
+                .context_mut()
+                .lookup_code_object(&Variable::from(key.as_str()))
+            {
+                Ok(builtin) => builtin,
+                _ => continue,
+            };
+            let names = names.clone();
+            let position = *position;
+            let wrapper = create_callable(move |vm| {
+                let mut args = pop_args(vm)?;
+                let bound = match args.get(position).map(unpack_closure) {
+                    Some(Ok((target, captured))) if !captured.is_empty() => {
+                        Some(names.borrow_mut().bind(vm, target, captured)?)
+                    }
+                    _ => None,
+                };
+                if let Some(name) = &bound {
+                    args[position] = Value::from(name.as_str());
+                }
+
+                let result = run_callable(vm, &builtin, args);
+                if let Some(name) = bound {
+                    names.borrow_mut().free.push(name);
+                }
+                result
+            });
+
+            replace_function(vm, key, wrapper)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Builtin function that failed last, together with the type and message of
+/// its error. Runtime errors carry no location, this is used to find the call
+/// site instead.
+pub(crate) type FailedBuiltin = Rc<RefCell<Option<(String, String, String)>>>;
+
+/// Module of the builtin functions. They record their name in `failed` when
+/// they return an error.
+pub(crate) fn std_module(failed: &FailedBuiltin) -> Module {
+    let mut module = lovm2_std::create_std_module();
+    for (name, builtin) in module.slots.iter_mut() {
+        let (name, failed, inner) = (name.to_string(), failed.clone(), builtin.clone());
+        *builtin = create_callable(move |vm| {
+            inner.run(vm).inspect_err(|e| {
+                let mut failed = failed.borrow_mut();
+                // builtins calling back into lol code fail after the callee
+                let ty = e.ty.to_string();
+                let known = matches!(&*failed, Some((_, t, msg)) if *t == ty && *msg == e.msg);
+                if !known {
+                    *failed = Some((name.clone(), ty, e.msg.clone()));
+                }
+            })
+        });
+    }
+    module
+}
+
+/// Names under which closures are registered while a builtin function uses
+/// them. Names are reused once the builtin returns.
+#[derive(Debug, Default)]
+struct ClosureNames {
+    count: usize,
+    free: Vec<String>,
+}
+
+impl ClosureNames {
+    fn bind(&mut self, vm: &mut Vm, target: String, captured: Vec<Value>) -> Lovm2Result<String> {
+        let name = match self.free.pop() {
+            Some(name) => name,
+            _ => {
+                self.count += 1;
+                format!("{{closure-{}}}", self.count)
+            }
+        };
+
+        let bound = create_callable(move |vm| {
+            let mut args = captured.clone();
+            args.extend(pop_args(vm)?);
+            run_with_args(vm, &target, args)
+        });
+
+        replace_function(vm, name.as_str(), bound)?;
+
+        Ok(name)
+    }
+}
+
+/// Pop the arguments of the current frame from the value stack.
+pub(crate) fn pop_args(vm: &mut Vm) -> Lovm2Result<Vec<Value>> {
+    let argn = vm.context_mut().frame_mut()?.argn;
+    let mut args = vec![];
+    for _ in 0..argn {
+        args.push(vm.context_mut().pop_value()?);
+    }
+    args.reverse();
+    Ok(args)
+}
+
+/// Run the function called `name` inside the current frame.
+fn run_with_args(vm: &mut Vm, name: &str, args: Vec<Value>) -> Lovm2Result<()> {
+    let co = vm.context_mut().lookup_code_object(&Variable::from(name))?;
+    run_callable(vm, &co, args)
+}
+
+fn run_callable(vm: &mut Vm, co: &CallableRef, args: Vec<Value>) -> Lovm2Result<()> {
+    vm.context_mut().frame_mut()?.argn = args.len() as u8;
+    for arg in args.into_iter() {
+        vm.context_mut().push_value(arg);
+    }
+
+    co.run(vm)
+}
+
+/// Split a function value into the name of the function and the values captured
+/// by a closure.
+fn unpack_closure(val: &Value) -> Lovm2Result<(String, Vec<Value>)> {
+    let mut val = val.clone();
+    val.unref_inplace()?;
+    match val {
+        Value::List(mut items) if !items.is_empty() => {
+            let name = items.remove(0).as_str_inner()?;
+            Ok((name, items))
+        }
+        val => Ok((val.as_str_inner()?, vec![])),
+    }
+}
+
+fn call(vm: &mut Vm) -> Lovm2Result<()> {
+    let mut args = pop_args(vm)?;
+    if args.is_empty() {
+        return err_from_string("no function to call");
+    }
+
+    let (name, mut captured) = unpack_closure(&args.remove(0))?;
+    captured.extend(args);
+    run_with_args(vm, &name, captured)
+}
+
+fn closure(vm: &mut Vm) -> Lovm2Result<()> {
+    let captured = pop_args(vm)?;
+    match captured.first() {
+        // make sure the lifted function exists before handing out a closure for it
+        Some(target) => vm
+            .context_mut()
+            .lookup_code_object(&Variable::from(target.as_str_inner()?))?,
+        _ => return err_from_string("no function to bind"),
+    };
+
+    vm.context_mut().push_value(Value::List(captured));
+
+    Ok(())
+}
+
+fn init(vm: &mut Vm) -> Lovm2Result<()> {
+    let args = pop_args(vm)?;
+    let name = match args.first() {
+        Some(name) => name.as_str_inner()?,
+        _ => return err_from_string("no initializer to run"),
+    };
+
+    // the initializer is marked first so functions called from it do not run it again
+    if vm.context_mut().value_of(&name).is_ok() {
+        vm.context_mut().push_value(Value::Nil);
+        return Ok(());
+    }
+    vm.context_mut().set_global(&name, Value::from(true));
+
+    run_with_args(vm, &name, vec![])
+}
+
+/// Arguments passed by name via `:name value`.
+#[derive(Clone, Debug, Default)]
+struct Keywords(Vec<(String, Value)>);
+
+fn keywords(vm: &mut Vm) -> Lovm2Result<()> {
+    let args = pop_args(vm)?;
+    let mut keywords = Keywords::default();
+    for pair in args.chunks(2) {
+        let val = pair.get(1).cloned().unwrap_or(Value::Nil);
+        keywords.0.push((pair[0].as_str_inner()?, val));
+    }
+    vm.context_mut().push_value(Value::create_any(keywords));
+    Ok(())
+}
+
+fn argument(vm: &mut Vm) -> Lovm2Result<()> {
+    let args = pop_args(vm)?;
+    let val = match args.as_slice() {
+        [values, idx] => values.get(idx)?,
+        _ => return err_from_string("invalid argument access"),
+    };
+    vm.context_mut().push_value(val);
+    Ok(())
+}
+
+fn argument_error<T>(msg: String) -> Lovm2Result<T> {
+    Err((Lovm2ErrorTy::Custom(ARGUMENT_ERROR.to_string()), msg).into())
+}
+
+fn bind(vm: &mut Vm) -> Lovm2Result<()> {
+    let spec = pop_args(vm)?;
+    let (name, required, optional, rest) = match spec.as_slice() {
+        [name, required, optional, rest, ..] => (
+            name.as_str_inner()?,
+            required.as_integer_inner()? as usize,
+            optional.as_integer_inner()? as usize,
+            rest.as_bool_inner()?,
+        ),
+        _ => return err_from_string("invalid parameters"),
+    };
+    let params = spec[4..]
+        .iter()
+        .map(Value::as_str_inner)
+        .collect::<Lovm2Result<Vec<_>>>()?;
+
+    // the arguments are still on the stack as the function did not take them
+    let lstack = vm.context_mut().lstack_mut();
+    let argn = match lstack.len().checked_sub(2) {
+        Some(idx) => lstack[idx].argn,
+        _ => 0,
+    };
+    let mut args = vec![];
+    for _ in 0..argn {
+        args.push(vm.context_mut().pop_value()?);
+    }
+    args.reverse();
+
+    let keywords = match args.last() {
+        Some(Value::Any(any)) => any.borrow().0.downcast_ref::<Keywords>().cloned(),
+        _ => None,
+    };
+    if keywords.is_some() {
+        args.pop();
+    }
+
+    let positional = required + optional;
+    if args.len() > positional && !rest {
+        return argument_error(format!(
+            "`{}` takes at most {} arguments, got {}",
+            name,
+            positional,
+            args.len()
+        ));
+    }
+    let remaining = args.split_off(args.len().min(positional));
+
+    let mut values: Vec<Option<Value>> = args.into_iter().map(Some).collect();
+    values.resize(positional, None);
+    for (key, val) in keywords.unwrap_or_default().0.into_iter() {
+        match params
+            .iter()
+            .take(positional)
+            .position(|param| *param == key)
+        {
+            Some(idx) if values[idx].is_some() => {
+                return argument_error(format!("`{}` got argument `{}` twice", name, key))
+            }
+            Some(idx) => values[idx] = Some(val),
+            _ => return argument_error(format!("`{}` has no parameter `{}`", name, key)),
+        }
+    }
+    if let Some(idx) = values[..required].iter().position(Option::is_none) {
+        return argument_error(format!("`{}` is missing argument `{}`", name, params[idx]));
+    }
+
+    // an explicit `nil` is a value as well, only missing arguments get the default
+    let missing = values[required..]
+        .iter()
+        .map(|val| Value::from(val.is_none()))
+        .collect();
+    let mut values: Vec<Value> = values
+        .into_iter()
+        .map(|val| val.unwrap_or(Value::Nil))
+        .collect();
+    if rest {
+        values.push(box_value(Value::List(remaining)));
+    }
+    values.push(box_value(Value::List(missing)));
+    // the values stay unboxed, so `{argument}` returns them as they were passed
+    let values = Value::Ref(Reference::from(Value::List(values)));
+    vm.context_mut().push_value(values);
+
+    Ok(())
+}
+
+/// Convert an error into a dict with the keys `type` and `message`.
+pub fn error_value(e: &Lovm2Error) -> Value {
+    let mut dict = Value::dict();
+    // setting a key on a fresh dict cannot fail
+    dict.set(&Value::from("type"), Value::from(e.ty.to_string()))
+        .unwrap();
+    dict.set(&Value::from("message"), Value::from(e.msg.clone()))
+        .unwrap();
+    box_value(dict)
+}
+
+fn raise(vm: &mut Vm) -> Lovm2Result<()> {
+    let val = vm.context_mut().pop_value()?;
+
+    // errors caught earlier can be raised again
+    let ty = val
+        .get(&Value::from("type"))
+        .and_then(|ty| ty.as_str_inner());
+    let msg = val
+        .get(&Value::from("message"))
+        .and_then(|msg| msg.as_str_inner());
+    let (ty, msg) = match (ty, msg) {
+        (Ok(ty), Ok(msg)) => (ty, msg),
+        _ => (RAISED_ERROR.to_string(), val.as_str_inner()?),
+    };
+
+    Err((Lovm2ErrorTy::Custom(ty), msg).into())
+}
+
+/// Run a lifted block inside the current frame and return its outcome.
+fn run_block(vm: &mut Vm, name: &str, locals: &[String]) -> Lovm2Result<Value> {
+    let co = vm.context_mut().lookup_code_object(&Variable::from(name))?;
+
+    // the block declares all locals as parameters. pass their current values
+    for local in locals.iter() {
+        let val = vm.context_mut().frame_mut()?.value_of(local).cloned();
+        vm.context_mut().push_value(val.unwrap_or(Value::Nil));
+    }
+
+    co.run(vm)?;
+    vm.context_mut().pop_value()
+}
+
+fn run_try(vm: &mut Vm) -> Lovm2Result<()> {
+    let args = pop_args(vm)?;
+    let (body, handler, var, cleanup) = match args.as_slice() {
+        [body, handler, var, cleanup, ..] => (body.as_str_inner()?, handler, var, cleanup),
+        _ => return err_from_string("invalid arguments for try"),
+    };
+    let locals = args[4..]
+        .iter()
+        .map(Value::as_str_inner)
+        .collect::<Lovm2Result<Vec<_>>>()?;
+
+    // run the blocks inside the frame of the calling function
+    let frame = vm.context_mut().lstack_mut().pop();
+    let lstack_len = vm.context_mut().lstack_mut().len();
+    let vstack_len = vm.context_mut().stack_mut().len();
+    let unwind = |vm: &mut Vm| {
+        vm.context_mut().lstack_mut().truncate(lstack_len);
+        vm.context_mut().stack_mut().truncate(vstack_len);
+    };
+
+    let mut outcome = run_block(vm, &body, &locals);
+
+    // scripts must not be able to ignore execution limits
+    let caught = matches!(&outcome, Err(e) if !is_limit_error(e));
+    if let (true, Err(e), Value::Str(handler)) = (caught, &outcome, handler) {
+        unwind(vm);
+        let var = var.as_str_inner()?;
+        let e = error_value(e);
+        vm.context_mut().frame_mut()?.set_local(var, e);
+        outcome = run_block(vm, handler, &locals);
+    }
+
+    if let Value::Str(cleanup) = cleanup {
+        if outcome.is_err() {
+            unwind(vm);
+        }
+        // the cleanup takes precedence if it leaves the block itself, but it
+        // must not replace an exceeded limit
+        let limited = matches!(&outcome, Err(e) if is_limit_error(e));
+        match run_block(vm, cleanup, &locals) {
+            Ok(Value::Int(0)) => {}
+            _ if limited => {}
+            result => outcome = result,
+        }
+    }
+
+    if outcome.is_err() {
+        unwind(vm);
+    }
+    vm.context_mut().lstack_mut().extend(frame);
+
+    vm.context_mut().push_value(outcome?);
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use lovm2::extend::create_callable;
+use lovm2::module::Module;
+use lovm2::prelude::*;
+use lovm2::vm::{LoadRequest, Vm};
+
+use crate::util::replace_function;
+
+/// Error type if a script uses a function or module the sandbox does not allow.
+pub const PERMISSION_DENIED: &str = "PermissionDenied";
+
+/// Builtin functions without access to the filesystem, network or terminal.
+/// All other builtins fail with [PERMISSION_DENIED] inside a sandbox unless
+/// allowed explicitly.
+pub const SAFE_FUNCTIONS: &[&str] = &[
+    "acos",
+    "all",
+    "any",
+    "append",
+    "argn",
+    "asin",
+    "atan",
+    "atan2",
+    "basename",
+    "call",
+    "captures",
+    "ceil",
+    "chr",
+    "clamp",
+    "contains",
+    "cos",
+    "decode",
+    "deep_clone",
+    "delete",
+    "e",
+    "encode",
+    "filter",
+    "floor",
+    "format",
+    "get",
+    "has_data",
+    "index_of",
+    "is_match",
+    "join",
+    "len",
+    "log",
+    "map",
+    "new_buffer",
+    "new_regex",
+    "ord",
+    "parent",
+    "pi",
+    "pop_vstack",
+    "push_vstack",
+    "read_line",
+    "readn",
+    "replace",
+    "round",
+    "set",
+    "sin",
+    "sort",
+    "split",
+    "sqrt",
+    "tan",
+    "to_lower",
+    "to_upper",
+    "trim",
+    "writes",
+];
+
+fn permission_denied<T>(msg: String) -> Lovm2Result<T> {
+    Err((Lovm2ErrorTy::Custom(PERMISSION_DENIED.to_string()), msg).into())
+}
+
+/// Restrictions for scripts that are not trusted. Only the listed modules can
+/// be imported and only builtin functions from [SAFE_FUNCTIONS] or the listed
+/// ones can be called.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sandbox {
+    /// Modules that can be loaded from disk.
+    pub modules: Vec<String>,
+    /// Builtin functions that stay available in addition to [SAFE_FUNCTIONS].
+    pub functions: Vec<String>,
+}
+
+impl Sandbox {
+    fn allows(&self, name: &str) -> bool {
+        SAFE_FUNCTIONS.contains(&name) || self.functions.iter().any(|allowed| allowed == name)
+    }
+
+    /// Replace all builtin functions that are not allowed with ones raising a
+    /// permission error.
+    pub(crate) fn install(&self, vm: &mut Vm) -> Lovm2Result<()> {
+        let builtins = lovm2_std::create_std_module();
+        let mut names: Vec<String> = builtins
+            .slots()
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .filter(|name| !self.allows(name))
+            .collect();
+        names.sort();
+
+        for name in names.iter() {
+            // builtins are reachable with and without their module name
+            for key in [name.to_string(), format!("std.{}", name)] {
+                let msg = format!("`{}` is not allowed inside the sandbox", name);
+                let denied = create_callable(move |_| permission_denied(msg.clone()));
+
+                replace_function(vm, key, denied)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wrap `hook` so it only loads the allowed modules.
+    pub(crate) fn load_hook<T>(
+        self,
+        hook: T,
+    ) -> impl Fn(&LoadRequest) -> Lovm2Result<Option<Module>> + 'static
+    where
+        T: Fn(&LoadRequest) -> Lovm2Result<Option<Module>> + 'static,
+    {
+        move |req| {
+            if !self.modules.contains(&req.module) {
+                return permission_denied(format!(
+                    "module `{}` is not allowed inside the sandbox",
+                    req.module
+                ));
+            }
+            hook(req)
+        }
+    }
+}
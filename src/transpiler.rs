@@ -1,217 +1,2420 @@
+use ess::span::ByteSpan;
 use ess::Sexp;
 use lovm2::prelude::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::error::{describe_parse_error, CompileError, CompileWarning, Location};
+use crate::runtime;
+use crate::util::{
+    fnv1a, module_candidates, namespaced, relative_module_candidates, source_root,
+    NAMESPACE_SEPARATOR,
+};
+use crate::LOL_EXTENSION;
+
+pub type CompileResult<T> = Result<T, CompileError>;
+
+/// Local variable receiving values of expressions used as statements.
+const DISCARDED: &str = "{discarded}";
+
+/// Name of the function collecting top-level expressions in script mode.
+pub const SCRIPT_ENTRY: &str = "{script}";
+
+/// Forms that are allowed on the top-level of a module.
+pub const TOPLEVEL_FORMS: &[&str] = &[
+    "const", "def", "defmacro", "defp", "defvar", "global", "import",
+];
+
+/// Forms declaring a global variable of the module.
+const GLOBAL_FORMS: &[&str] = &["const", "defvar", "global"];
+
+/// Upper bound for nested macro expansions. Stops macros that expand to themselves.
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// How a builtin macro is translated.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Builtin {
+    /// Produces a value and can be used as expression.
+    Expression,
+    /// Does not produce a value.
+    Statement,
+    /// Only valid inside of the templates of `defmacro`.
+    Template,
+}
+
+/// Names that are handled by the transpiler itself and never end up as function
+/// calls. Forms not listed here are translated as calls.
+const BUILTINS: &[(&str, Builtin)] = &[
+    ("+", Builtin::Expression),
+    ("-", Builtin::Expression),
+    ("*", Builtin::Expression),
+    ("/", Builtin::Expression),
+    ("%", Builtin::Expression),
+    ("eq", Builtin::Expression),
+    ("ne", Builtin::Expression),
+    ("ge", Builtin::Expression),
+    ("gt", Builtin::Expression),
+    ("le", Builtin::Expression),
+    ("lt", Builtin::Expression),
+    ("and", Builtin::Expression),
+    ("bool", Builtin::Expression),
+    ("or", Builtin::Expression),
+    ("break", Builtin::Statement),
+    ("case", Builtin::Expression),
+    ("cond", Builtin::Expression),
+    ("continue", Builtin::Statement),
+    ("dict", Builtin::Expression),
+    ("do", Builtin::Expression),
+    ("float", Builtin::Expression),
+    ("foreach", Builtin::Statement),
+    ("fn", Builtin::Expression),
+    ("if", Builtin::Expression),
+    ("import", Builtin::Statement),
+    ("import-global", Builtin::Statement),
+    ("int", Builtin::Expression),
+    ("lambda", Builtin::Expression),
+    ("let", Builtin::Expression),
+    ("list", Builtin::Expression),
+    ("loop", Builtin::Statement),
+    ("not", Builtin::Expression),
+    ("quote", Builtin::Expression),
+    ("raise", Builtin::Statement),
+    ("range", Builtin::Expression),
+    ("ret", Builtin::Statement),
+    ("set!", Builtin::Statement),
+    ("set-global", Builtin::Statement),
+    ("str", Builtin::Expression),
+    ("try", Builtin::Statement),
+    ("unless", Builtin::Statement),
+    ("when", Builtin::Statement),
+    ("while", Builtin::Statement),
+    ("quasiquote", Builtin::Template),
+    ("unquote", Builtin::Template),
+    ("unquote-splicing", Builtin::Template),
+];
+
+fn builtin(name: &str) -> Option<Builtin> {
+    BUILTINS
+        .iter()
+        .find(|(builtin, _)| *builtin == name)
+        .map(|(_, kind)| *kind)
+}
+
+/// Names of all builtin macros and top-level forms.
+pub fn builtin_macros() -> impl Iterator<Item = &'static str> {
+    let toplevel = TOPLEVEL_FORMS.iter().filter(|name| builtin(name).is_none());
+    BUILTINS
+        .iter()
+        .map(|(name, _)| *name)
+        .chain(toplevel.copied())
+}
 
 macro_rules! take_as {
-    ($expr:expr, $ty:path) => {
+    ($self:expr, $expr:expr, $ty:path) => {
         match $expr {
             $ty(inner, _loc) => Ok(inner),
-            _ => Err(format!("expected {}, got {:?}", stringify!($ty), $expr)),
+            other => Err($self.unexpected(form_name(stringify!($ty)), other)),
+        }
+    };
+}
+
+fn form_name(ty: &str) -> &'static str {
+    match ty {
+        "Sexp::Sym" => "symbol",
+        "Sexp::Str" => "string",
+        "Sexp::Char" => "character",
+        "Sexp::Int" => "integer",
+        "Sexp::Float" => "float",
+        "Sexp::List" => "list",
+        _ => "form",
+    }
+}
+
+/// Check if `sexp` is a list starting with the symbol `name`.
+fn is_form(sexp: &Sexp, name: &str) -> bool {
+    matches!(sexp, Sexp::List(list, _) if matches!(list.first(), Some(Sexp::Sym(head, _)) if head == name))
+}
+
+/// Collect the variables a macro template binds via `let`, `foreach` or `fn`.
+/// Unquoted parts are skipped as they are provided by the caller.
+fn bound_variables(sexp: &Sexp, bound: &mut Vec<String>) {
+    let list = match sexp {
+        Sexp::List(list, _) => list,
+        _ => return,
+    };
+
+    let mut add = |sexp: &Sexp| {
+        if let Sexp::Sym(name, _) = sexp {
+            if !bound.iter().any(|other| other == name) {
+                bound.push(name.to_string());
+            }
+        }
+    };
+
+    match list.as_slice() {
+        [Sexp::Sym(head, _), ..] if head == "unquote" || head == "unquote-splicing" => return,
+        [Sexp::Sym(head, _), pattern, ..] if head == "let" => match let_bindings(list) {
+            Some((bindings, _)) => {
+                // malformed bindings are reported when the expansion is compiled
+                for binding in bindings.iter() {
+                    if let Sexp::List(binding, _) = binding {
+                        if let Some(pattern) = binding.first() {
+                            pattern_variables(pattern).iter().for_each(&mut add);
+                        }
+                    }
+                }
+            }
+            _ => pattern_variables(pattern).iter().for_each(add),
+        },
+        [Sexp::Sym(head, _), Sexp::List(head_list, _), ..]
+        | [Sexp::Sym(head, _), Sexp::Sym(_, _), Sexp::List(head_list, _), ..]
+            if head == "foreach" && head_list.len() == 2 =>
+        {
+            add(&head_list[1])
+        }
+        [Sexp::Sym(head, _), Sexp::List(params, _), ..] if head == "fn" || head == "lambda" => {
+            params.iter().for_each(add)
+        }
+        _ => {}
+    }
+
+    for item in list.iter() {
+        bound_variables(item, bound);
+    }
+}
+
+/// Collect the names a `let` pattern binds.
+fn pattern_variables<'a>(pattern: &Sexp<'a>) -> Vec<Sexp<'a>> {
+    if let Some(items) = delimited(pattern, BRACES) {
+        // the keys of dict patterns are values
+        return items
+            .iter()
+            .step_by(2)
+            .flat_map(pattern_variables)
+            .collect();
+    }
+    match pattern {
+        Sexp::Sym(..) => vec![pattern.clone()],
+        Sexp::List(list, _) => {
+            let items = delimited(pattern, BRACKETS).unwrap_or(list);
+            items.iter().flat_map(pattern_variables).collect()
+        }
+        _ => vec![],
+    }
+}
+
+/// Lists written with brackets or braces start with one of these markers after
+/// parsing. They cannot clash with symbols as the parser reads both as lists.
+const BRACKETS: &str = "{brackets}";
+const BRACES: &str = "{braces}";
+
+/// Items of `sexp` if it was written with the delimiters of `marker`.
+fn delimited<'a, 'b>(sexp: &'a Sexp<'b>, marker: &str) -> Option<&'a [Sexp<'b>]> {
+    match sexp {
+        Sexp::List(list, _) if is_form(sexp, marker) => Some(&list[1..]),
+        _ => None,
+    }
+}
+
+/// Bindings and body of a scoped `let`. It is written as
+/// `(let [(pattern value)...] body...)` or as `(let (pattern value)... body)`
+/// with more than two operands. `None` if the form binds for the rest of the
+/// block instead.
+#[allow(clippy::type_complexity)]
+fn let_bindings<'a, 'b>(list: &'a [Sexp<'b>]) -> Option<(&'a [Sexp<'b>], &'a [Sexp<'b>])> {
+    match list.get(1).and_then(|first| delimited(first, BRACKETS)) {
+        Some(bindings) => Some((bindings, &list[2..])),
+        _ if list.len() > 3 => Some(list[1..].split_at(list.len() - 2)),
+        _ => None,
+    }
+}
+
+/// Copy `sexp` and point all of its locations to `span`.
+fn relocate(sexp: &Sexp, span: ByteSpan) -> Sexp<'static> {
+    match sexp {
+        Sexp::Sym(name, _) => Sexp::Sym(name.to_string().into(), span),
+        Sexp::Str(s, _) => Sexp::Str(s.to_string().into(), span),
+        Sexp::Char(c, _) => Sexp::Char(*c, span),
+        Sexp::Int(n, _) => Sexp::Int(*n, span),
+        Sexp::Float(n, _) => Sexp::Float(*n, span),
+        Sexp::List(list, _) => {
+            Sexp::List(list.iter().map(|item| relocate(item, span)).collect(), span)
+        }
+    }
+}
+
+/// Quoted symbols become strings and quoted lists become lists.
+fn quoted(sexp: &Sexp) -> Expr {
+    match sexp {
+        Sexp::Sym(name, _) | Sexp::Str(name, _) => Expr::from(name.as_ref()),
+        Sexp::Char(c, _) => Expr::from(format!("{}", c)),
+        Sexp::Int(n, _) => Expr::from(*n),
+        Sexp::Float(n, _) => Expr::from(*n),
+        Sexp::List(list, _) => {
+            let list = delimited(sexp, BRACKETS)
+                .or_else(|| delimited(sexp, BRACES))
+                .unwrap_or(list);
+            let mut ls = Initialize::new(Value::list().into());
+            for item in list.iter() {
+                ls.add(quoted(item));
+            }
+            ls.into()
+        }
+    }
+}
+
+/// Collect all symbols used inside `sexp`.
+fn symbols(sexp: &Sexp) -> Vec<String> {
+    match sexp {
+        Sexp::Sym(name, _) => vec![name.to_string()],
+        Sexp::List(list, _) => list.iter().flat_map(symbols).collect(),
+        _ => vec![],
+    }
+}
+
+/// Stands in for the colon of keywords like `:as` during parsing as the
+/// parser does not accept symbols starting with a colon.
+const KEYWORD_MARKER: char = '\u{1}';
+
+/// Parse `source` into expressions. Keywords are read as symbols starting
+/// with a colon and lists written with brackets or braces start with a marker.
+fn parse(source: &str) -> (Vec<Sexp<'static>>, Option<ess::parser::ParseError>) {
+    let mut masked = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    let mut token_start = true;
+
+    // the marker has the same length as the colon so locations stay valid
+    while let Some(c) = chars.next() {
+        match c {
+            ':' if token_start => masked.push(KEYWORD_MARKER),
+            '"' => {
+                masked.push(c);
+                while let Some(c) = chars.next() {
+                    masked.push(c);
+                    match c {
+                        '\\' => masked.extend(chars.next()),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            ';' => {
+                masked.push(c);
+                for c in chars.by_ref() {
+                    masked.push(c);
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '#' if chars.peek() == Some(&'\\') => {
+                masked.push(c);
+                masked.extend(chars.next());
+                masked.extend(chars.next());
+            }
+            _ => masked.push(c),
+        }
+        token_start = c.is_whitespace() || "([{'`,@".contains(c);
+    }
+
+    let (sexprs, err) = ess::parser::parse(&masked);
+    let sexprs = sexprs.iter().map(|sexp| unmask(&masked, sexp)).collect();
+    (sexprs, err)
+}
+
+fn unmask(source: &str, sexp: &Sexp) -> Sexp<'static> {
+    match sexp {
+        Sexp::Sym(name, loc) if name.starts_with(KEYWORD_MARKER) => {
+            Sexp::Sym(name.replacen(KEYWORD_MARKER, ":", 1).into(), *loc)
+        }
+        Sexp::List(list, loc) => {
+            let marker = match source.as_bytes().get(loc.0) {
+                Some(b'[') => Some(BRACKETS),
+                Some(b'{') => Some(BRACES),
+                _ => None,
+            };
+            let marker = marker.map(|marker| Sexp::Sym(marker.into(), *loc));
+            let items = list.iter().map(|item| unmask(source, item));
+            Sexp::List(marker.into_iter().chain(items).collect(), *loc)
+        }
+        other => other.to_owned(),
+    }
+}
+
+/// Derive a short identifier from the file path and content of a module.
+fn module_id(file: Option<&str>, source: &str) -> String {
+    let hash = fnv1a(file.unwrap_or_default().bytes().chain(source.bytes()));
+    format!("{:016x}", hash)
+}
+
+fn describe(sexp: &Sexp) -> String {
+    match sexp {
+        Sexp::Sym(name, _) => format!("symbol `{}`", name),
+        Sexp::Str(s, _) => format!("string {:?}", s),
+        Sexp::Char(c, _) => format!("character {:?}", c),
+        Sexp::Int(n, _) => format!("integer `{}`", n),
+        Sexp::Float(n, _) => format!("float `{}`", n),
+        Sexp::List(list, _) if list.is_empty() => "empty list".to_string(),
+        Sexp::List(list, _) => match &list[0] {
+            Sexp::Sym(name, _) => format!("form `{}`", name),
+            _ => "list".to_string(),
+        },
+    }
+}
+
+/// Check if the function name was chosen by the transpiler. Such names contain
+/// braces and therefore cannot clash with names written in lol.
+pub fn is_generated(name: &str) -> bool {
+    name.starts_with('{')
+}
+
+/// Prefix of functions defined via `defp`. They are only callable from inside
+/// their module.
+const PRIVATE_PREFIX: &str = "{private}";
+
+/// Check if the function was defined via `defp`. Such functions are never
+/// added to the vm and only reachable by calls from inside their module.
+pub fn is_private(name: &str) -> bool {
+    name.starts_with(PRIVATE_PREFIX)
+}
+
+/// Name a function defined via `defp` was given in its source.
+pub fn private_name(name: &str) -> Option<&str> {
+    name.strip_prefix(PRIVATE_PREFIX)
+}
+
+/// Variable receiving the arguments of a function in the order of its parameters.
+const ARGUMENTS: &str = "{args}";
+
+/// Variable receiving the value of `ret` inside of `try` blocks.
+const TRY_RETURN: &str = "{ret}";
+/// Variable receiving the outcome of a `try` block.
+const TRY_STATUS: &str = "{try-status}";
+/// Outcomes of a block lifted from `try`. They tell the surrounding function
+/// which control flow has to be continued.
+const TRY_COMPLETED: i64 = 0;
+const TRY_RETURNED: i64 = 1;
+const TRY_BROKE: i64 = 2;
+const TRY_CONTINUED: i64 = 3;
+
+/// Control flow leaving a block that was lifted from `try`.
+#[derive(Clone, Copy, Default)]
+struct TryContext {
+    /// Number of loops inside the lifted block enclosing the current form.
+    loop_depth: usize,
+    returns: bool,
+    breaks: bool,
+    continues: bool,
+}
+
+/// Ways of leaving an iteration of a loop.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Jump {
+    Break,
+    Continue,
+}
+
+/// A loop enclosing the form that is currently translated.
+#[derive(Clone, Debug, Default)]
+struct LoopContext {
+    /// Name given via `(loop :label ...)`.
+    label: Option<String>,
+    /// Labeled `break` and `continue` inside this loop that target an outer one.
+    escapes: Vec<(String, Jump)>,
+    /// Whether a `break` leaves this loop.
+    breaks: bool,
+}
+
+/// Local variable signaling a labeled `break` or `continue` to the loops
+/// enclosing the one that was left.
+fn jump_flag(label: &str, jump: Jump) -> String {
+    match jump {
+        Jump::Break => format!("{{break-{}}}", label),
+        Jump::Continue => format!("{{continue-{}}}", label),
+    }
+}
+
+/// A macro defined via `(defmacro name (params) body)`.
+#[derive(Clone, Debug)]
+struct Macro {
+    params: Vec<String>,
+    /// Parameter following `&rest`. It receives all remaining operands as list.
+    rest: Option<String>,
+    body: Sexp<'static>,
+    /// Variables bound by the template itself. They are renamed on every
+    /// expansion so they cannot clash with variables at the call site.
+    bound: Vec<String>,
+}
+
+/// Parameters of a function defined via `def`.
+struct Signature<'a> {
+    /// Name of every parameter and the default value of optional ones.
+    params: Vec<(String, Option<&'a Sexp<'a>>)>,
+    /// Parameter following `&rest`. It receives all remaining arguments as list.
+    rest: Option<String>,
+}
+
+/// A module imported via `(import name :as alias :only (names))`.
+#[derive(Clone, Debug)]
+struct Import {
+    module: String,
+    /// Replaces the module name in front of its functions.
+    alias: Option<String>,
+    /// Functions that can be used. They are callable without module name as well.
+    only: Option<Vec<String>>,
+}
+
+impl Import {
+    fn allows(&self, name: &str) -> bool {
+        match &self.only {
+            Some(only) => only.iter().any(|allowed| allowed == name),
+            _ => true,
+        }
+    }
+}
+
+/// A variable declared via `const`, `defvar` or `global`.
+#[derive(Clone, Debug)]
+struct Global {
+    /// Name of the lovm2 global holding the value. Globals of different
+    /// modules cannot clash.
+    key: String,
+    constant: bool,
+}
+
+/// State of a single macro expansion.
+struct Expansion {
+    args: HashMap<String, Sexp<'static>>,
+    renames: HashMap<String, String>,
+    /// Location of the macro call. Generated forms are reported there.
+    site: ByteSpan,
+}
+
+#[derive(Default)]
+pub struct Transpiler {
+    file: Option<String>,
+    /// Source root of `file`. Imported modules are searched up to it.
+    root: Option<PathBuf>,
+    /// Directories searched for imported modules after the directory of `file`.
+    search_paths: Vec<PathBuf>,
+    source: String,
+    script: bool,
+    /// Translating the top-level forms of a script. Variables bound there stay
+    /// available for later builds like globals.
+    toplevel: bool,
+    /// Identifies the module in names of lifted functions.
+    module_id: String,
+    lift_count: usize,
+    /// Functions created from `fn` expressions that are not yet part of the module.
+    lifted: Vec<(String, Hir)>,
+    /// Local variables of the function that is currently translated.
+    locals: Vec<String>,
+    /// Locals of the enclosing function that the current `fn` captured. They
+    /// hold copies of the values, so `set!` rejects them.
+    captures: Vec<String>,
+    /// Variables bound by `let` for a body only, innermost last. They map the
+    /// name to the local holding the value.
+    scopes: Vec<Vec<(String, String)>>,
+    /// Set while translating a block lifted from `try`.
+    try_context: Option<TryContext>,
+    /// Loops enclosing the current form, innermost last.
+    loops: Vec<LoopContext>,
+    /// Macros known to the transpiler. They stay defined for later builds.
+    macros: HashMap<String, Macro>,
+    expansion_count: usize,
+    /// Numbers local variables introduced by the transpiler.
+    temp_count: usize,
+    /// Statements that have to run before the expression being translated,
+    /// e.g. the branches of an `if` in expression position.
+    hoisted: Option<Block>,
+    /// Files of imported modules whose macros were read during the last build.
+    macro_sources: Vec<PathBuf>,
+    warnings: Vec<CompileWarning>,
+    /// Imports with options. Like macros, they stay in effect for later builds.
+    imports: Vec<Import>,
+    /// Functions of the current module defined via `defp`.
+    private: Vec<String>,
+    /// Module and name of functions that imported modules define via `defp`.
+    hidden: Vec<(String, String)>,
+    /// Global variables of the current module. In script mode, they stay
+    /// declared for later builds.
+    globals: HashMap<String, Global>,
+    /// Module and name of constants that imported modules define via `const`.
+    constants: Vec<(String, String)>,
+    separator: Option<String>,
+}
+
+impl Transpiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept expressions and statements on the top-level as well. They are
+    /// compiled into a function named [SCRIPT_ENTRY] that returns the value of
+    /// the last expression.
+    pub fn script_mode(mut self) -> Self {
+        self.script = true;
+        self
+    }
+
+    /// Join module and function names using `separator` instead of `-`. The
+    /// interpreter running the module has to use the same separator.
+    pub fn separator(mut self, separator: &str) -> Self {
+        self.separator = Some(separator.to_string());
+        self
+    }
+
+    /// Search imported modules inside `dir` as well. Their macros, constants
+    /// and private functions are only known if the module is found.
+    pub fn add_search_path<T>(&mut self, dir: T)
+    where
+        T: AsRef<Path>,
+    {
+        self.search_paths.push(dir.as_ref().to_path_buf());
+    }
+
+    fn sep(&self) -> &str {
+        self.separator.as_deref().unwrap_or(NAMESPACE_SEPARATOR)
+    }
+
+    /// Files of imported modules whose macros were read during the last build.
+    /// The compiled module has to be rebuilt if one of them changes.
+    pub fn macro_sources(&self) -> &[PathBuf] {
+        &self.macro_sources
+    }
+
+    /// Warnings emitted during the last build.
+    pub fn warnings(&self) -> &[CompileWarning] {
+        &self.warnings
+    }
+
+    fn maps_to_operator(&self, name: &str) -> Option<Operator2> {
+        match name {
+            "+" => Some(Operator2::Add),
+            "-" => Some(Operator2::Sub),
+            "*" => Some(Operator2::Mul),
+            "/" => Some(Operator2::Div),
+            "%" => Some(Operator2::Rem),
+            "eq" => Some(Operator2::Equal),
+            "ne" => Some(Operator2::NotEqual),
+            "ge" => Some(Operator2::GreaterEqual),
+            "gt" => Some(Operator2::GreaterThan),
+            "le" => Some(Operator2::LessEqual),
+            "lt" => Some(Operator2::LessThan),
+            "and" => Some(Operator2::And),
+            "or" => Some(Operator2::Or),
+            _ => None,
+        }
+    }
+
+    fn locate(&self, span: &ByteSpan) -> Location {
+        Location::new(self.file.clone(), &self.source, *span)
+    }
+
+    fn unexpected(&self, expected: &'static str, got: &Sexp) -> CompileError {
+        CompileError::UnexpectedForm {
+            expected,
+            got: describe(got),
+            loc: self.locate(got.get_loc()),
+        }
+    }
+
+    /// Make sure that the form `list` has between `min` and `max` operands.
+    /// The first item of `list` is the name of the form and does not count.
+    fn expect_operands(
+        &self,
+        list: &[Sexp],
+        loc: &ByteSpan,
+        min: usize,
+        max: Option<usize>,
+    ) -> CompileResult<()> {
+        let got = list.len().saturating_sub(1);
+
+        if min <= got && max.is_none_or(|max| got <= max) {
+            return Ok(());
+        }
+
+        let expected = match max {
+            Some(max) if max == min => format!("exactly {}", min),
+            Some(max) => format!("{} to {}", min, max),
+            None => format!("at least {}", min),
+        };
+        let name = match list.first() {
+            Some(Sexp::Sym(name, _)) => name.to_string(),
+            _ => "form".to_string(),
+        };
+
+        Err(CompileError::OperandCount {
+            name,
+            expected,
+            got,
+            loc: self.locate(loc),
+        })
+    }
+
+    /// Split a form into its name and list of items.
+    fn take_form<'a, 'b>(&self, ast: &'a Sexp<'b>) -> CompileResult<(&'a str, &'a [Sexp<'b>])> {
+        let list = take_as!(self, ast, Sexp::List)?;
+        // brackets only have a meaning for `let`
+        let list = delimited(ast, BRACKETS).unwrap_or(list);
+        match list.first() {
+            Some(Sexp::Sym(name, _)) => Ok((name.as_ref(), list)),
+            Some(other) => Err(self.unexpected("symbol", other)),
+            None => Err(self.unexpected("form", ast)),
+        }
+    }
+
+    pub fn build_from_path<T>(&mut self, path: T) -> CompileResult<Module>
+    where
+        T: AsRef<std::path::Path>,
+    {
+        // derive the module name from filepath
+        let name = match path.as_ref().file_stem() {
+            Some(stem) => stem.to_string_lossy().to_string(),
+            _ => DEFAULT_MODULE_NAME.to_string(),
+        };
+        self.build_from_path_as(path, &name)
+    }
+
+    /// Like [Transpiler::build_from_path] but with an explicit module name. This
+    /// is required for nested modules like `net.http`.
+    pub fn build_from_path_as<T>(&mut self, path: T, name: &str) -> CompileResult<Module>
+    where
+        T: AsRef<std::path::Path>,
+    {
+        let file = path.as_ref().display().to_string();
+        let source = std::fs::read_to_string(path.as_ref()).map_err(|e| CompileError::Io {
+            file: file.clone(),
+            msg: format!("{}", e),
+        })?;
+        let meta = ModuleMeta::new(name.to_string(), Some(file.clone()), vec![]);
+        self.root = path
+            .as_ref()
+            .parent()
+            .and_then(|dir| source_root(dir, name))
+            .map(Path::to_path_buf);
+        self.build_source(meta, source, Some(file))
+    }
+
+    pub fn build<T>(&mut self, meta: lovm2::prelude::ModuleMeta, source: T) -> CompileResult<Module>
+    where
+        T: AsRef<str>,
+    {
+        self.root = None;
+        self.build_source(meta, source, None)
+    }
+
+    fn build_source<T>(
+        &mut self,
+        meta: lovm2::prelude::ModuleMeta,
+        source: T,
+        file: Option<String>,
+    ) -> CompileResult<Module>
+    where
+        T: AsRef<str>,
+    {
+        self.file = file;
+        self.source = source.as_ref().to_string();
+        self.module_id = module_id(self.file.as_deref(), &self.source);
+        self.lift_count = 0;
+        self.loops.clear();
+        self.scopes.clear();
+        self.captures.clear();
+        self.macro_sources.clear();
+        self.warnings.clear();
+
+        let mut builder = ModuleBuilder::with_meta(meta);
+
+        if !source.as_ref().is_empty() {
+            let (sexprs, err) = parse(source.as_ref());
+            if let Some(err) = err {
+                let (msg, span) = describe_parse_error(&err);
+                return Err(CompileError::Parse {
+                    msg,
+                    loc: self.locate(&span),
+                });
+            }
+
+            let sexprs = self.expand_module(&sexprs)?;
+
+            // build hir
+            self.translate(&mut builder, &sexprs)?;
+        }
+
+        let module = builder.build().map_err(|e| CompileError::Lowering(e.msg))?;
+
+        Ok(module)
+    }
+
+    fn macro_error(&self, msg: String, span: &ByteSpan) -> CompileError {
+        CompileError::Macro {
+            msg,
+            loc: self.locate(span),
+        }
+    }
+
+    /// Register the macros of a module and expand all of their usages.
+    fn expand_module(&mut self, sexprs: &[Sexp]) -> CompileResult<Vec<Sexp<'static>>> {
+        let mut forms = vec![];
+
+        for sexpr in sexprs.iter() {
+            match sexpr {
+                Sexp::List(list, loc) if is_form(sexpr, "defmacro") => {
+                    let (name, mac) = self.parse_macro(list, loc)?;
+                    self.macros.insert(name, mac);
+                }
+                Sexp::List(list, loc) if is_form(sexpr, "import") => {
+                    let import = self.parse_import(list, loc)?;
+                    self.read_import(&import);
+                    self.add_import(import);
+                    forms.push(sexpr);
+                }
+                _ => forms.push(sexpr),
+            }
+        }
+
+        forms.into_iter().map(|form| self.expand(form, 0)).collect()
+    }
+
+    fn parse_macro(&self, list: &[Sexp], loc: &ByteSpan) -> CompileResult<(String, Macro)> {
+        self.expect_operands(list, loc, 3, Some(3))?;
+
+        let name = take_as!(self, &list[1], Sexp::Sym)?;
+        let mut params = vec![];
+        let mut rest = None;
+        let mut items = take_as!(self, &list[2], Sexp::List)?.iter();
+
+        while let Some(param) = items.next() {
+            let param = take_as!(self, param, Sexp::Sym)?;
+            if param != "&rest" {
+                params.push(param.to_string());
+                continue;
+            }
+
+            match (items.next(), items.next()) {
+                (Some(name), None) => rest = Some(take_as!(self, name, Sexp::Sym)?.to_string()),
+                (None, _) => {
+                    let msg = "`&rest` must be followed by a parameter".to_string();
+                    return Err(self.macro_error(msg, list[2].get_loc()));
+                }
+                (_, Some(other)) => return Err(self.unexpected("end of parameters", other)),
+            }
+        }
+
+        let mut bound = vec![];
+        bound_variables(&list[3], &mut bound);
+
+        let mac = Macro {
+            params,
+            rest,
+            body: list[3].to_owned(),
+            bound,
+        };
+
+        Ok((name.to_string(), mac))
+    }
+
+    /// Parse `(import name)` followed by the options `:as alias` and
+    /// `:only (names)`.
+    fn parse_import(&self, list: &[Sexp], loc: &ByteSpan) -> CompileResult<Import> {
+        self.expect_operands(list, loc, 1, Some(5))?;
+
+        let mut import = Import {
+            module: take_as!(self, &list[1], Sexp::Sym)?.to_string(),
+            alias: None,
+            only: None,
+        };
+        let mut options = list[2..].iter();
+
+        while let Some(option) = options.next() {
+            let value = match options.next() {
+                Some(value) => value,
+                _ => return Err(self.unexpected("value of import option", option)),
+            };
+
+            match take_as!(self, option, Sexp::Sym)?.as_ref() {
+                ":as" => import.alias = Some(take_as!(self, value, Sexp::Sym)?.to_string()),
+                ":only" => {
+                    let mut names = vec![];
+                    for name in take_as!(self, value, Sexp::List)?.iter() {
+                        names.push(take_as!(self, name, Sexp::Sym)?.replace("_", "-"));
+                    }
+                    import.only = Some(names);
+                }
+                _ => return Err(self.unexpected("`:as` or `:only`", option)),
+            }
+        }
+
+        Ok(import)
+    }
+
+    /// Remember imports that change how calls are resolved. A later import of
+    /// the same module replaces the earlier one.
+    fn add_import(&mut self, import: Import) {
+        self.imports.retain(|other| other.module != import.module);
+        if import.alias.is_some() || import.only.is_some() {
+            self.imports.push(import);
+        }
+    }
+
+    /// Text in front of the functions of an imported module e.g. `net-http-`.
+    fn import_prefix(&self, import: &Import) -> String {
+        match &import.alias {
+            Some(alias) => format!("{}{}", alias, self.sep()),
+            _ => namespaced(&import.module, "", self.sep()),
+        }
+    }
+
+    /// Map a call of an imported function to the name it is loaded under. Calls
+    /// using an alias or names listed in `:only` are rewritten, names excluded
+    /// by `:only` are rejected.
+    fn resolve_import(&self, name: &str, loc: &ByteSpan) -> CompileResult<String> {
+        let mut found: Option<(&Import, usize)> = None;
+
+        for import in self.imports.iter() {
+            let prefix = self.import_prefix(import);
+            let matches = name.len() > prefix.len() && name.starts_with(&prefix);
+            // the most specific prefix wins e.g. `net-http-` over `net-`
+            if matches && found.is_none_or(|(_, len)| len <= prefix.len()) {
+                found = Some((import, prefix.len()));
+            }
+        }
+
+        let (import, function) = match found {
+            Some((import, len)) => (import, &name[len..]),
+            _ => match self.imports.iter().rev().find(|import| {
+                matches!(&import.only, Some(only) if only.iter().any(|allowed| allowed == name))
+            }) {
+                Some(import) => (import, name),
+                _ => return Ok(name.to_string()),
+            },
+        };
+
+        if !import.allows(function) {
+            return Err(CompileError::Import {
+                msg: format!(
+                    "`{}` is not imported from module `{}`",
+                    function, import.module
+                ),
+                loc: self.locate(loc),
+            });
+        }
+
+        Ok(namespaced(&import.module, function, self.sep()))
+    }
+
+    /// Reject calls of functions that an imported module defines via `defp`.
+    fn check_visibility(&self, name: &str, loc: &ByteSpan) -> CompileResult<()> {
+        for (module, function) in self.hidden.iter() {
+            if namespaced(module, function, self.sep()) == name {
+                return Err(CompileError::Import {
+                    msg: format!("function `{}` of module `{}` is private", function, module),
+                    loc: self.locate(loc),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Make the macros of an imported module available under the prefix of the
+    /// import e.g. `module-name` and remember its constants and private
+    /// functions. The module is searched like the interpreter does: next to the
+    /// current file first, then inside the search paths.
+    fn read_import(&mut self, import: &Import) {
+        let module = import.module.as_str();
+        let extensions = [LOL_EXTENSION];
+        let mut candidates = match self.file.as_ref().and_then(|file| Path::new(file).parent()) {
+            Some(dir) => relative_module_candidates(module, dir, self.root.as_deref(), &extensions),
+            _ => vec![],
+        };
+        for dir in self.search_paths.iter() {
+            candidates.extend(module_candidates(module, dir, &extensions));
+        }
+        let path = match candidates.into_iter().find(|path| path.is_file()) {
+            Some(path) => path,
+            _ => return,
+        };
+        let source = match std::fs::read_to_string(&path) {
+            Ok(source) => source,
+            _ => return,
+        };
+        self.macro_sources.push(path);
+        self.hidden.retain(|(hidden, _)| hidden != module);
+        self.constants.retain(|(constant, _)| constant != module);
+
+        let (sexprs, _) = parse(&source);
+        for sexpr in sexprs.iter() {
+            if let Sexp::List(list, loc) = sexpr {
+                if is_form(sexpr, "defp") || is_form(sexpr, "const") {
+                    if let Some(Sexp::Sym(name, _)) = list.get(1) {
+                        let entry = (module.to_string(), name.to_string());
+                        match is_form(sexpr, "defp") {
+                            true => self.hidden.push(entry),
+                            _ => self.constants.push(entry),
+                        }
+                    }
+                    continue;
+                }
+                if !is_form(sexpr, "defmacro") {
+                    continue;
+                }
+                // invalid definitions are reported when the module itself is compiled
+                let (name, mac) = match self.parse_macro(list, loc) {
+                    Ok(parsed) if import.allows(&parsed.0) => parsed,
+                    _ => continue,
+                };
+                let prefix = self.import_prefix(import);
+                if import.only.is_some() {
+                    self.macros.insert(name.clone(), mac.clone());
+                }
+                self.macros.insert(format!("{}{}", prefix, name), mac);
+            }
+        }
+    }
+
+    fn expand(&mut self, sexp: &Sexp, depth: usize) -> CompileResult<Sexp<'static>> {
+        let (list, loc) = match sexp {
+            Sexp::List(list, loc) => (list, loc),
+            _ => return Ok(sexp.to_owned()),
+        };
+
+        match list.first() {
+            Some(Sexp::Sym(name, _)) if name == "quote" => Ok(sexp.to_owned()),
+            Some(Sexp::Sym(name, _)) if self.macros.contains_key(name.as_ref()) => {
+                if depth == MAX_EXPANSION_DEPTH {
+                    let msg = format!("expansion of macro `{}` does not terminate", name);
+                    return Err(self.macro_error(msg, loc));
+                }
+
+                let expanded = self.expand_macro(name, list, loc)?;
+                self.expand(&expanded, depth + 1)
+            }
+            _ => {
+                let mut items = vec![];
+                for item in list.iter() {
+                    items.push(self.expand(item, depth)?);
+                }
+                Ok(Sexp::List(items, *loc))
+            }
+        }
+    }
+
+    fn expand_macro(
+        &mut self,
+        name: &str,
+        list: &[Sexp],
+        loc: &ByteSpan,
+    ) -> CompileResult<Sexp<'static>> {
+        let mac = self.macros[name].clone();
+        let max = match mac.rest {
+            Some(_) => None,
+            _ => Some(mac.params.len()),
+        };
+        self.expect_operands(list, loc, mac.params.len(), max)?;
+
+        let operands = &list[1..];
+        let mut args: HashMap<String, Sexp<'static>> = mac
+            .params
+            .iter()
+            .cloned()
+            .zip(operands.iter().map(Sexp::to_owned))
+            .collect();
+
+        if let Some(rest) = &mac.rest {
+            let rest_args = operands[mac.params.len()..].iter().map(Sexp::to_owned);
+            args.insert(rest.clone(), Sexp::List(rest_args.collect(), *loc));
+        }
+
+        // braces cannot be written in lol which makes the new names unique
+        self.expansion_count += 1;
+        let renames = mac
+            .bound
+            .iter()
+            .map(|var| (var.clone(), format!("{{{}-{}}}", var, self.expansion_count)))
+            .collect();
+
+        let expansion = Expansion {
+            args,
+            renames,
+            site: *loc,
+        };
+
+        self.eval_macro(&mac.body, &expansion)
+    }
+
+    /// Evaluate the body of a macro. It is either a parameter, a literal or a
+    /// (quasi)quoted form.
+    fn eval_macro(&self, expr: &Sexp, exp: &Expansion) -> CompileResult<Sexp<'static>> {
+        match expr {
+            Sexp::Sym(name, _) => exp.args.get(name.as_ref()).cloned().ok_or_else(|| {
+                self.macro_error(format!("unknown macro parameter `{}`", name), &exp.site)
+            }),
+            Sexp::List(list, _) => match list.as_slice() {
+                [Sexp::Sym(head, _), form] if head == "quote" => Ok(relocate(form, exp.site)),
+                [Sexp::Sym(head, _), template] if head == "quasiquote" => {
+                    self.expand_template(template, exp)
+                }
+                _ => {
+                    let msg = format!(
+                        "expected parameter or quoted form in macro, got {}",
+                        describe(expr)
+                    );
+                    Err(self.macro_error(msg, &exp.site))
+                }
+            },
+            other => Ok(relocate(other, exp.site)),
+        }
+    }
+
+    fn expand_template(&self, template: &Sexp, exp: &Expansion) -> CompileResult<Sexp<'static>> {
+        let list = match template {
+            Sexp::Sym(name, _) => {
+                let name = match exp.renames.get(name.as_ref()) {
+                    Some(renamed) => renamed.clone(),
+                    _ => name.to_string(),
+                };
+                return Ok(Sexp::Sym(name.into(), exp.site));
+            }
+            Sexp::List(list, _) => list,
+            other => return Ok(relocate(other, exp.site)),
+        };
+
+        match list.as_slice() {
+            [Sexp::Sym(head, _), expr] if head == "unquote" => return self.eval_macro(expr, exp),
+            [Sexp::Sym(head, _), _] if head == "unquote-splicing" => {
+                let msg = "`unquote-splicing` must be used inside of a list".to_string();
+                return Err(self.macro_error(msg, &exp.site));
+            }
+            _ => {}
+        }
+
+        let mut items = vec![];
+        for item in list.iter() {
+            match item {
+                Sexp::List(inner, _) if is_form(item, "unquote-splicing") && inner.len() == 2 => {
+                    match self.eval_macro(&inner[1], exp)? {
+                        Sexp::List(spliced, _) => items.extend(spliced),
+                        other => {
+                            let msg = format!(
+                                "`unquote-splicing` expects a list, got {}",
+                                describe(&other)
+                            );
+                            return Err(self.macro_error(msg, &exp.site));
+                        }
+                    }
+                }
+                _ => items.push(self.expand_template(item, exp)?),
+            }
+        }
+
+        Ok(Sexp::List(items, exp.site))
+    }
+
+    fn translate(&mut self, builder: &mut ModuleBuilder, sexprs: &[Sexp]) -> CompileResult<()> {
+        let mut script = vec![];
+
+        // private functions can be called before they are defined
+        self.private = sexprs
+            .iter()
+            .filter(|sexpr| is_form(sexpr, "defp"))
+            .filter_map(|sexpr| match sexpr {
+                Sexp::List(list, _) => match list.get(1) {
+                    Some(Sexp::Sym(name, _)) => Some(name.to_string()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+
+        // globals are accessible from functions defined before them as well
+        if !self.script {
+            self.globals.clear();
+        }
+        for sexpr in sexprs.iter() {
+            if let Some((name, list)) = self.global_form(sexpr)? {
+                self.declare_global(name, list, sexpr.get_loc())?;
+            }
+        }
+        let mut initializers = vec![];
+
+        for sexpr in sexprs.iter() {
+            let form = match sexpr {
+                Sexp::List(list, _) if !list.is_empty() => Some(self.take_form(sexpr)?),
+                _ => None,
+            };
+
+            match form {
+                Some(("def", list)) | Some(("defp", list)) => {
+                    self.translate_define(builder, list, sexpr.get_loc())?
+                }
+                Some((name, list)) if GLOBAL_FORMS.contains(&name) => match self.script {
+                    true => script.push(sexpr),
+                    _ => initializers.push(list),
+                },
+                Some(("import", list)) => {
+                    self.translate_toplevel_import(builder, list, sexpr.get_loc())?
+                }
+                _ if self.script => script.push(sexpr),
+                _ => {
+                    return Err(CompileError::UnexpectedToplevel {
+                        got: describe(sexpr),
+                        loc: self.locate(sexpr.get_loc()),
+                    })
+                }
+            }
+        }
+
+        if self.script {
+            self.translate_script(builder, &script)?;
+        } else if !initializers.is_empty() {
+            self.translate_globals(builder, &initializers)?;
+        }
+
+        for (name, hir) in self.lifted.drain(..) {
+            *builder.add(name) = hir;
+        }
+
+        Ok(())
+    }
+
+    /// Split a declaration of a global variable into its form name and items.
+    fn global_form<'a, 'b>(
+        &self,
+        sexp: &'a Sexp<'b>,
+    ) -> CompileResult<Option<(&'a str, &'a [Sexp<'b>])>> {
+        if !GLOBAL_FORMS.iter().any(|name| is_form(sexp, name)) {
+            return Ok(None);
+        }
+        self.take_form(sexp).map(Some)
+    }
+
+    fn declare_global(&mut self, form: &str, list: &[Sexp], loc: &ByteSpan) -> CompileResult<()> {
+        let constant = form == "const";
+        match constant {
+            true => self.expect_operands(list, loc, 2, Some(2))?,
+            _ => self.expect_operands(list, loc, 1, Some(2))?,
+        }
+
+        let name = take_as!(self, &list[1], Sexp::Sym)?;
+        let key = match self.script {
+            true => name.to_string(),
+            _ => format!("{{{}-{}}}", self.module_id, name),
+        };
+        self.globals
+            .insert(name.to_string(), Global { key, constant });
+
+        Ok(())
+    }
+
+    /// Name of the function assigning the globals of the current module.
+    fn initializer(&self) -> String {
+        format!("{{init-{}}}", self.module_id)
+    }
+
+    /// Make sure the globals of the module are assigned before a function
+    /// that can be called from outside of it runs.
+    fn initialize_globals(&self, block: &mut Block) {
+        if !self.script && !self.globals.is_empty() {
+            let init = Call::with_args(runtime::INIT, vec![Expr::from(self.initializer())]);
+            block.step(init);
+        }
+    }
+
+    /// Assign the value of `(const name value)` or `(defvar name value)`.
+    fn translate_global(&mut self, block: &mut Block, list: &[Sexp]) -> CompileResult<()> {
+        let name = take_as!(self, &list[1], Sexp::Sym)?;
+        let key = self.globals[name.as_ref()].key.clone();
+        let val = match list.get(2) {
+            Some(val) => self.translate_value(block, val)?,
+            _ => Value::Nil.into(),
+        };
+        block.step(Assign::global(&Variable::from(key), val));
+        Ok(())
+    }
+
+    /// Create the initializer of the module globals and a function returning
+    /// the value for every constant so importers can read them.
+    fn translate_globals(
+        &mut self,
+        module: &mut ModuleBuilder,
+        lists: &[&[Sexp]],
+    ) -> CompileResult<()> {
+        self.locals.clear();
+        self.scopes = vec![vec![]];
+        let mut init = Hir::new();
+        for list in lists.iter() {
+            self.translate_global(init.block_mut(), list)?;
+        }
+        *module.add(self.initializer()) = init;
+
+        for (name, global) in self.globals.iter() {
+            if global.constant {
+                let hir = module.add(name.to_string());
+                self.initialize_globals(hir.block_mut());
+                hir.step(Return::value(Variable::from(global.key.clone())));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn translate_script(
+        &mut self,
+        module: &mut ModuleBuilder,
+        forms: &[&Sexp],
+    ) -> CompileResult<()> {
+        let hir = module.add(SCRIPT_ENTRY);
+        self.locals.clear();
+        self.scopes = vec![vec![]];
+        self.toplevel = true;
+        let result = self.translate_toplevel(hir.block_mut(), forms);
+        self.toplevel = false;
+        result
+    }
+
+    fn translate_toplevel(&mut self, block: &mut Block, forms: &[&Sexp]) -> CompileResult<()> {
+        for (i, form) in forms.iter().enumerate() {
+            let is_last = i + 1 == forms.len();
+
+            if let Some((_, list)) = self.global_form(form)? {
+                self.translate_global(block, list)?;
+            } else if self.is_statement(form) {
+                self.translate_macro(block, form)?;
+            } else if is_last {
+                let val = self.translate_value(block, form)?;
+                block.step(Return::value(val));
+            } else if let Sexp::List(..) = form {
+                // evaluate calls for their side effects
+                self.translate_macro(block, form)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check if the form is a builtin macro that does not produce a value.
+    fn is_statement(&self, sexp: &Sexp) -> bool {
+        match sexp {
+            Sexp::List(list, _) => matches!(
+                list.first(),
+                Some(Sexp::Sym(name, _)) if builtin(name) == Some(Builtin::Statement)
+            ),
+            _ => false,
+        }
+    }
+
+    fn translate_toplevel_import(
+        &self,
+        module: &mut ModuleBuilder,
+        list: &[Sexp],
+        loc: &ByteSpan,
+    ) -> CompileResult<()> {
+        let import = self.parse_import(list, loc)?;
+        module.add_dependency(import.module);
+        Ok(())
+    }
+
+    fn translate_define(
+        &mut self,
+        module: &mut ModuleBuilder,
+        list: &[Sexp],
+        loc: &ByteSpan,
+    ) -> CompileResult<()> {
+        self.expect_operands(list, loc, 2, None)?;
+
+        let name = take_as!(self, &list[1], Sexp::Sym)?;
+        let key = match self.private.iter().any(|private| private == name) {
+            true => format!("{}{}", PRIVATE_PREFIX, name),
+            _ => name.to_string(),
+        };
+        let signature = self.take_signature(&list[2])?;
+        let params = signature.params.iter().map(|(param, _)| param);
+        self.scopes = vec![params
+            .chain(signature.rest.iter())
+            .map(|param| (param.clone(), param.clone()))
+            .collect()];
+
+        let body = &list[3..];
+        let is_private = is_private(&key);
+        let hir = module.add(key);
+        hir.step(Interrupt::new(runtime::CHECKPOINT));
+        self.translate_signature(hir.block_mut(), name, &signature)?;
+        if !is_private {
+            self.initialize_globals(hir.block_mut());
+        }
+
+        self.translate_function_body(hir.block_mut(), name, body)
+    }
+
+    /// Translate the body of a function that returns the value of its last form.
+    fn translate_function_body(
+        &mut self,
+        block: &mut Block,
+        name: &str,
+        body: &[Sexp],
+    ) -> CompileResult<()> {
+        if let Some((last, init)) = body.split_last() {
+            for stmt in init.iter() {
+                self.translate_macro(block, stmt)?;
+            }
+            self.translate_implicit_return(block, name, last)?;
+        }
+
+        Ok(())
+    }
+
+    /// Return the value of the last form of a function body.
+    fn translate_implicit_return(
+        &mut self,
+        block: &mut Block,
+        name: &str,
+        last: &Sexp,
+    ) -> CompileResult<()> {
+        if self.is_statement(last) {
+            // the value of `main` is not used by anyone
+            if name != ENTRY_POINT && !is_form(last, "ret") && !is_form(last, "raise") {
+                let (form, _) = self.take_form(last)?;
+                self.warnings.push(CompileWarning {
+                    msg: format!("`{}` has no value, `{}` will return nil", form, name),
+                    loc: self.locate(last.get_loc()),
+                });
+            }
+            return self.translate_macro(block, last);
+        }
+
+        let val = self.translate_value(block, last)?;
+        block.step(Return::value(val));
+        Ok(())
+    }
+
+    /// Read the parameter list of `def`. Parameters are required, optional as
+    /// `(name default)` or collect the remaining arguments after `&rest`.
+    fn take_signature<'a>(&mut self, ast: &'a Sexp<'a>) -> CompileResult<Signature<'a>> {
+        let mut params = vec![];
+        let mut rest = None;
+        let mut items = take_as!(self, ast, Sexp::List)?.iter();
+
+        while let Some(param) = items.next() {
+            match param {
+                Sexp::Sym(name, _) if name == "&rest" => match (items.next(), items.next()) {
+                    (Some(name), None) => rest = Some(take_as!(self, name, Sexp::Sym)?.to_string()),
+                    (None, _) => return Err(self.unexpected("parameter after `&rest`", ast)),
+                    (_, Some(other)) => return Err(self.unexpected("end of parameters", other)),
+                },
+                Sexp::Sym(name, _) => {
+                    // required parameters cannot be passed after optional ones
+                    if params.iter().any(|(_, default)| Option::is_some(default)) {
+                        return Err(self.unexpected("optional parameter", param));
+                    }
+                    params.push((name.to_string(), None));
+                }
+                Sexp::List(optional, _) if optional.len() == 2 => {
+                    let name = take_as!(self, &optional[0], Sexp::Sym)?;
+                    params.push((name.to_string(), Some(&optional[1])));
+                }
+                _ => return Err(self.unexpected("parameter", param)),
+            }
+        }
+
+        self.locals = params.iter().map(|(name, _)| name.clone()).collect();
+        self.locals.extend(rest.clone());
+        Ok(Signature { params, rest })
+    }
+
+    /// Assign the arguments to the parameters. They are checked by
+    /// [runtime::BIND] which also resolves arguments passed by keyword.
+    fn translate_signature(
+        &mut self,
+        block: &mut Block,
+        name: &str,
+        signature: &Signature,
+    ) -> CompileResult<()> {
+        let required = signature
+            .params
+            .iter()
+            .filter(|(_, default)| default.is_none())
+            .count();
+        let optional = signature.params.len() - required;
+
+        let mut spec = vec![
+            Expr::from(name),
+            Expr::from(required as i64),
+            Expr::from(optional as i64),
+            Expr::from(signature.rest.is_some()),
+        ];
+        spec.extend(
+            signature
+                .params
+                .iter()
+                .map(|(param, _)| Expr::from(param.as_str())),
+        );
+        let args = Variable::from(ARGUMENTS);
+        block.step(Assign::local(&args, Call::with_args(runtime::BIND, spec)));
+
+        let params = signature
+            .params
+            .iter()
+            .map(|(param, default)| (param, *default));
+        let rest = signature.rest.iter().map(|param| (param, None));
+        // flags of the optional parameters that were not passed follow the values
+        let missing = signature.params.len() + signature.rest.iter().count();
+        for (i, (param, default)) in params.chain(rest).enumerate() {
+            let param = Variable::from(param.as_str());
+            let val = Call::with_args(
+                runtime::ARGUMENT,
+                vec![args.clone().into(), Expr::from(i as i64)],
+            );
+            block.step(Assign::local(&param, val));
+
+            // the default is evaluated on every call that does not pass a value
+            if let Some(default) = default {
+                let missing = Access::new(
+                    args.clone(),
+                    vec![
+                        Expr::from(missing as i64),
+                        Expr::from((i - required) as i64),
+                    ],
+                );
+                let target = block.branch().add_condition(missing.into());
+                let val = self.translate_value(target, default)?;
+                target.step(Assign::local(&param, val));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read a parameter list and make the names known as locals.
+    fn take_parameters(&mut self, ast: &Sexp) -> CompileResult<Vec<String>> {
+        let mut params = vec![];
+        for param in take_as!(self, ast, Sexp::List)?.iter() {
+            let param = take_as!(self, param, Sexp::Sym)?;
+            params.push(param.to_string());
+        }
+        self.locals = params.clone();
+        Ok(params)
+    }
+
+    /// Lift the body of `(fn (args) body...)` into a function of its own. Local
+    /// variables of the surrounding function that are used inside the body are
+    /// captured by value and passed as leading arguments.
+    fn translate_lambda(&mut self, list: &[Sexp], loc: &ByteSpan) -> CompileResult<Expr> {
+        self.expect_operands(list, loc, 1, None)?;
+
+        let outer = std::mem::take(&mut self.locals);
+        let params = self.take_parameters(&list[1])?;
+        let body = &list[2..];
+
+        let mut captures = vec![];
+        for name in body.iter().flat_map(symbols) {
+            let local = match self.local_name(&name) {
+                Some(local) => local.to_string(),
+                _ => continue,
+            };
+            if outer.contains(&local) && !params.contains(&name) && !captures.contains(&local) {
+                captures.push(local);
+            }
+        }
+
+        self.lift_count += 1;
+        let name = format!("{{fn-{}-{}}}", self.module_id, self.lift_count);
+
+        self.locals = captures.iter().chain(params.iter()).cloned().collect();
+        let outer_captures = std::mem::replace(&mut self.captures, captures.clone());
+        let arguments = self.locals.iter().cloned().map(Variable::from).collect();
+        let outer_try = self.try_context.take();
+        let outer_loops = std::mem::take(&mut self.loops);
+        let mut hir = Hir::with_args(arguments);
+        hir.step(Interrupt::new(runtime::CHECKPOINT));
+        // parameters shadow variables bound in the surrounding function
+        self.scopes
+            .push(params.iter().map(|p| (p.clone(), p.clone())).collect());
+        let result = self.translate_function_body(hir.block_mut(), "fn", body);
+        self.scopes.pop();
+        result?;
+        self.lifted.push((name.clone(), hir));
+        self.locals = outer;
+        self.captures = outer_captures;
+        self.try_context = outer_try;
+        self.loops = outer_loops;
+
+        if captures.is_empty() {
+            return Ok(Expr::from(name));
+        }
+
+        let mut args = vec![Expr::from(name)];
+        for capture in captures.into_iter() {
+            args.push(Expr::from(Variable::from(capture)));
+        }
+        Ok(Call::with_args(runtime::CLOSURE, args).into())
+    }
+
+    /// Lift the blocks of `(try body... (catch e handler...) (finally ...))` into
+    /// functions. They are run by [runtime::TRY] inside the frame of the current
+    /// function and therefore share its local variables.
+    fn translate_try(
+        &mut self,
+        block: &mut Block,
+        list: &[Sexp],
+        loc: &ByteSpan,
+    ) -> CompileResult<()> {
+        self.expect_operands(list, loc, 1, None)?;
+
+        let mut body = vec![];
+        let mut catch = None;
+        let mut finally = None;
+
+        for form in list[1..].iter() {
+            match form {
+                Sexp::List(clause, clause_loc)
+                    if is_form(form, "catch") && finally.is_none() && catch.is_none() =>
+                {
+                    self.expect_operands(clause, clause_loc, 1, None)?;
+                    let var = take_as!(self, &clause[1], Sexp::Sym)?;
+                    catch = Some((var.to_string(), &clause[2..]));
+                }
+                Sexp::List(clause, _) if is_form(form, "finally") && finally.is_none() => {
+                    finally = Some(&clause[1..]);
+                }
+                _ if catch.is_some() || finally.is_some() => {
+                    return Err(self.unexpected("`catch` or `finally`", form));
+                }
+                _ => body.push(form.clone()),
+            }
         }
-    };
-}
 
-pub struct Transpiler;
+        let handler = catch
+            .as_ref()
+            .map(|(_, handler)| *handler)
+            .unwrap_or_default();
 
-impl Transpiler {
-    pub fn new() -> Self {
-        Self
-    }
+        let caught = catch
+            .as_ref()
+            .map(|(var, _)| (var.clone(), self.allocate_local(var)));
+        if let Some((_, local)) = &caught {
+            block.step(Assign::local(&Variable::from(local.as_str()), Value::Nil));
+        }
+        block.step(Assign::local(&Variable::from(TRY_RETURN), Value::Nil));
 
-    fn maps_to_operator(&self, name: &str) -> Option<Operator2> {
-        match name {
-            "+" => Some(Operator2::Add),
-            "-" => Some(Operator2::Sub),
-            "*" => Some(Operator2::Mul),
-            "/" => Some(Operator2::Div),
-            "%" => Some(Operator2::Rem),
-            "eq" => Some(Operator2::Equal),
-            "ne" => Some(Operator2::NotEqual),
-            "ge" => Some(Operator2::GreaterEqual),
-            "gt" => Some(Operator2::GreaterThan),
-            "le" => Some(Operator2::LessEqual),
-            "lt" => Some(Operator2::LessThan),
-            "and" => Some(Operator2::And),
-            "or" => Some(Operator2::Or),
-            _ => None,
+        // the blocks receive the locals visible here. variables they bind
+        // themselves are dropped at their end
+        let shared = self.locals.clone();
+        let outer = self.try_context.replace(TryContext::default());
+        let body = self.lift_try_block(&body, &shared, vec![])?;
+        let handler = match &caught {
+            Some(binding) => {
+                let binding = vec![binding.clone()];
+                Expr::from(self.lift_try_block(handler, &shared, binding)?)
+            }
+            _ => Value::Nil.into(),
+        };
+        let cleanup = match finally {
+            Some(cleanup) => Expr::from(self.lift_try_block(cleanup, &shared, vec![])?),
+            _ => Value::Nil.into(),
+        };
+        let escapes = std::mem::replace(&mut self.try_context, outer).unwrap_or_default();
+
+        let var = match caught {
+            Some((_, local)) => Expr::from(local),
+            _ => Value::Nil.into(),
+        };
+        let mut args = vec![Expr::from(body), handler, var, cleanup];
+        args.extend(shared.iter().map(|name| Expr::from(name.as_str())));
+
+        let status = Variable::from(TRY_STATUS);
+        block.step(Assign::local(&status, Call::with_args(runtime::TRY, args)));
+
+        let outcome =
+            |code: i64| Expr::from_opn(Operator2::Equal, vec![status.clone().into(), code.into()]);
+        let branch = block.branch();
+        if escapes.returns {
+            let returned = branch.add_condition(outcome(TRY_RETURNED));
+            self.translate_return(returned, Variable::from(TRY_RETURN).into());
+        }
+        if escapes.breaks {
+            self.translate_break(branch.add_condition(outcome(TRY_BROKE)));
+        }
+        if escapes.continues {
+            self.translate_continue(branch.add_condition(outcome(TRY_CONTINUED)));
         }
+
+        Ok(())
     }
 
-    pub fn build_from_path<T>(&mut self, path: T) -> Result<Module, String>
-    where
-        T: AsRef<std::path::Path>,
-    {
-        let source = std::fs::read_to_string(path.as_ref()).map_err(|e| format!("{}", e))?;
-        // derive the module name and location from filepath
-        let meta: ModuleMeta = path.as_ref().into();
-        self.build(meta, source)
+    fn lift_try_block(
+        &mut self,
+        forms: &[Sexp],
+        shared: &[String],
+        scope: Vec<(String, String)>,
+    ) -> CompileResult<String> {
+        self.lift_count += 1;
+        let name = format!("{{try-{}-{}}}", self.module_id, self.lift_count);
+
+        let arguments = shared.iter().cloned().map(Variable::from).collect();
+        let mut hir = Hir::with_args(arguments);
+        hir.step(Interrupt::new(runtime::CHECKPOINT));
+        self.scopes.push(scope);
+        let result = self.translate_body(hir.block_mut(), forms, None);
+        self.scopes.pop();
+        result?;
+        hir.step(Return::value(TRY_COMPLETED));
+
+        self.lifted.push((name.clone(), hir));
+        Ok(name)
+    }
+
+    fn translate_return(&mut self, block: &mut Block, val: Expr) {
+        match &mut self.try_context {
+            Some(ctx) => {
+                ctx.returns = true;
+                block.step(Assign::local(&Variable::from(TRY_RETURN), val));
+                block.step(Return::value(TRY_RETURNED));
+            }
+            _ => block.step(Return::value(val)),
+        }
     }
 
-    pub fn build<T>(
+    /// `(cond (test body...) ... (else body...))` runs the body of the first
+    /// clause whose test is true. If `var` is given, the value of the body is
+    /// assigned to it.
+    fn translate_cond(
         &mut self,
-        meta: lovm2::prelude::ModuleMeta,
-        source: T,
-    ) -> Result<Module, String>
-    where
-        T: AsRef<str>,
-    {
-        let mut builder = ModuleBuilder::with_meta(meta);
+        block: &mut Block,
+        clauses: &[Sexp],
+        var: Option<&str>,
+    ) -> CompileResult<()> {
+        let (clause, rest) = match clauses.split_first() {
+            Some(split) => split,
+            _ => return Ok(()),
+        };
+        let items = take_as!(self, clause, Sexp::List)?;
+        let (test, body) = match items.split_first() {
+            Some(split) => split,
+            _ => return Err(self.unexpected("`(test body...)`", clause)),
+        };
 
-        if !source.as_ref().is_empty() {
-            let (sexprs, err) = ess::parser::parse(source.as_ref());
-            if let Some(err) = err {
-                return Err(format!("{:?}", err));
+        if let Sexp::Sym(name, _) = test {
+            if name == "else" {
+                if let Some(next) = rest.first() {
+                    return Err(self.unexpected("`else` as last clause", next));
+                }
+                return self.translate_block(block, body, var);
             }
+        }
 
-            // build hir
-            self.translate(&mut builder, &sexprs)?;
+        // the following tests are only evaluated if this one fails
+        let condition = self.translate_value(block, test)?;
+        let branch = block.branch();
+        self.translate_block(branch.add_condition(condition), body, var)?;
+        if !rest.is_empty() {
+            self.translate_cond(branch.default_condition(), rest, var)?;
         }
 
-        let module = builder.build().map_err(|e| format!("{:?}", e))?;
+        Ok(())
+    }
 
-        Ok(module)
+    /// `(case value (literal body...) ((literal...) body...) (else body...))`
+    /// runs the body of the first clause matching `value`. If `var` is given,
+    /// the value of that body is assigned to it.
+    fn translate_case(
+        &mut self,
+        block: &mut Block,
+        rest: &[Sexp],
+        var: Option<&str>,
+    ) -> CompileResult<()> {
+        // evaluate the value only once
+        let val = self.translate_value(block, &rest[0])?;
+        let subject = self.temporary("case");
+        block.step(Assign::local(&Variable::from(subject.clone()), val));
+
+        let mut clauses = vec![];
+        for clause in rest[1..].iter() {
+            let items = take_as!(self, clause, Sexp::List)?;
+            let (pattern, body) = match items.split_first() {
+                Some(split) => split,
+                _ => return Err(self.unexpected("`(literal body...)`", clause)),
+            };
+
+            let literals = match pattern {
+                Sexp::Sym(name, _) if name == "else" => {
+                    clauses.push(Sexp::List(items.to_vec(), *clause.get_loc()));
+                    continue;
+                }
+                Sexp::List(literals, _) if !is_form(pattern, "quote") => literals.as_slice(),
+                _ => std::slice::from_ref(pattern),
+            };
+
+            // turn the clause into one of `cond` comparing against every literal
+            let mut test = vec![Sexp::Sym("or".into(), *pattern.get_loc())];
+            for literal in literals.iter() {
+                match literal {
+                    Sexp::Int(..) | Sexp::Float(..) | Sexp::Str(..) | Sexp::Char(..) => {}
+                    _ if is_form(literal, "quote") => {}
+                    _ => return Err(self.unexpected("literal", literal)),
+                }
+                let loc = *literal.get_loc();
+                let subject = Sexp::Sym(subject.clone().into(), loc);
+                test.push(Sexp::List(
+                    vec![Sexp::Sym("eq".into(), loc), subject, literal.clone()],
+                    loc,
+                ));
+            }
+            // `or` expects two operands at least
+            let test = match test.len() {
+                2 => test.pop().unwrap(),
+                _ => Sexp::List(test, *pattern.get_loc()),
+            };
+
+            let mut items = vec![test];
+            items.extend(body.iter().cloned());
+            clauses.push(Sexp::List(items, *clause.get_loc()));
+        }
+
+        self.translate_cond(block, &clauses, var)
     }
 
-    fn translate(&mut self, builder: &mut ModuleBuilder, sexprs: &[Sexp]) -> Result<(), String> {
-        for sexpr in sexprs.iter() {
-            match sexpr {
-                Sexp::List(list, _) => {
-                    if let Sexp::Sym(name, _) = &list[0] {
-                        match name.as_ref() {
-                            "def" => self.translate_define(builder, &list)?,
-                            "import" => self.translate_toplevel_import(builder, &list)?,
-                            _ => return Err(format!("unexpected keyword `{}`", name)),
-                        }
-                    } else {
-                        unimplemented!()
-                    }
+    fn translate_break(&mut self, block: &mut Block) {
+        match &mut self.try_context {
+            Some(ctx) if ctx.loop_depth == 0 => {
+                ctx.breaks = true;
+                block.step(Return::value(TRY_BROKE));
+            }
+            _ => {
+                if let Some(ctx) = self.loops.last_mut() {
+                    ctx.breaks = true;
                 }
-                _ => panic!("not expected at top-level: {:?}", sexpr),
+                block.step(Break::new());
             }
         }
+    }
 
-        Ok(())
+    fn translate_continue(&mut self, block: &mut Block) {
+        match &mut self.try_context {
+            Some(ctx) if ctx.loop_depth == 0 => {
+                ctx.continues = true;
+                block.step(Return::value(TRY_CONTINUED));
+            }
+            _ => block.step(Continue::new()),
+        }
     }
 
-    fn translate_toplevel_import(
-        &self,
-        module: &mut ModuleBuilder,
+    /// `(break label)` and `(continue label)` leave all loops inside the
+    /// labeled one. They set a flag that is checked after each of them.
+    fn translate_jump(
+        &mut self,
+        block: &mut Block,
         list: &[Sexp],
-    ) -> Result<(), String> {
-        let name = take_as!(&list[1], Sexp::Sym)?;
-        module.add_dependency(name.to_string());
+        loc: &ByteSpan,
+        jump: Jump,
+    ) -> CompileResult<()> {
+        self.expect_operands(list, loc, 0, Some(1))?;
+
+        let target = match list.get(1) {
+            Some(sexp) => {
+                let label = take_as!(self, sexp, Sexp::Sym)?.trim_start_matches(':');
+                let target = self
+                    .loops
+                    .iter()
+                    .rposition(|ctx| ctx.label.as_deref() == Some(label));
+                match target {
+                    Some(target) => Some((label.to_string(), target)),
+                    _ => return Err(self.unexpected("label of an enclosing loop", sexp)),
+                }
+            }
+            _ => None,
+        };
+
+        match target {
+            Some((label, target)) if target + 1 < self.loops.len() => {
+                let flag = Variable::from(jump_flag(&label, jump));
+                block.step(Assign::local(&flag, Value::Bool(true)));
+                self.translate_break(block);
+
+                let escapes = &mut self.loops.last_mut().unwrap().escapes;
+                if !escapes.contains(&(label.clone(), jump)) {
+                    escapes.push((label, jump));
+                }
+            }
+            _ if jump == Jump::Break => self.translate_break(block),
+            _ => self.translate_continue(block),
+        }
+
         Ok(())
     }
 
-    fn translate_define(&self, module: &mut ModuleBuilder, list: &[Sexp]) -> Result<(), String> {
-        let name = take_as!(&list[1], Sexp::Sym)?;
-        let arguments = take_as!(&list[2], Sexp::List)?
+    /// Split the label from the operands of a loop.
+    fn take_label<'a, 'b>(&self, rest: &'a [Sexp<'b>]) -> (Option<String>, &'a [Sexp<'b>]) {
+        match rest.first() {
+            Some(Sexp::Sym(label, _)) if label.starts_with(':') => {
+                (Some(label[1..].to_string()), &rest[1..])
+            }
+            _ => (None, rest),
+        }
+    }
+
+    /// Prepare the flags of a labeled loop and track it as enclosing loop.
+    fn enter_labeled_loop(&mut self, block: &mut Block, label: Option<String>) {
+        if let Some(label) = &label {
+            for jump in [Jump::Break, Jump::Continue] {
+                let flag = jump_flag(label, jump);
+                block.step(Assign::local(
+                    &Variable::from(flag.as_str()),
+                    Value::Bool(false),
+                ));
+                self.declare_local(&flag);
+            }
+        }
+        self.loops.push(LoopContext {
+            label,
+            escapes: vec![],
+            breaks: false,
+        });
+        self.enter_loop();
+    }
+
+    /// Continue labeled jumps that left the loop that was just translated
+    /// inside of the enclosing one.
+    fn leave_labeled_loop(&mut self, block: &mut Block) {
+        self.leave_loop();
+        let escapes = self.loops.pop().unwrap_or_default().escapes;
+
+        for (label, jump) in escapes.into_iter() {
+            let flag = Variable::from(jump_flag(&label, jump));
+            let target = block.branch().add_condition(Expr::from(flag.clone()));
+            let parent = self.loops.last_mut().unwrap();
+
+            if parent.label.as_deref() == Some(label.as_str()) {
+                target.step(Assign::local(&flag, Value::Bool(false)));
+                match jump {
+                    Jump::Break => self.translate_break(target),
+                    Jump::Continue => self.translate_continue(target),
+                }
+            } else {
+                if !parent.escapes.contains(&(label.clone(), jump)) {
+                    parent.escapes.push((label, jump));
+                }
+                self.translate_break(target);
+            }
+        }
+    }
+
+    /// Name of the local holding the variable `name` in the current scope.
+    /// Variables introduced by the transpiler are not bound to a scope.
+    fn local_name<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        let bound = self
+            .scopes
             .iter()
-            .map(|item| take_as!(item, Sexp::Sym).unwrap())
-            .map(|item| Variable::from(item.to_string()))
-            .collect();
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|(bound, _)| bound == name)
+            .map(|(_, local)| local.as_str());
+        match bound {
+            None if name.starts_with('{') && self.locals.iter().any(|local| local == name) => {
+                Some(name)
+            }
+            _ => bound,
+        }
+    }
 
-        // TODO: avoid index errors here
-        let body = &list[3..];
-        let hir = module.add_with_args(name.to_string(), arguments);
+    /// Choose the local for a new variable `name`. A variable of the same name
+    /// that is bound in an enclosing scope must keep its value.
+    fn allocate_local(&mut self, name: &str) -> String {
+        let visible = self.scopes.iter().flatten().any(|(_, local)| local == name);
+        if !visible {
+            self.declare_local(name);
+            return name.to_string();
+        }
+        self.temp_count += 1;
+        let local = format!("{{{}:{}}}", name, self.temp_count);
+        self.declare_local(&local);
+        local
+    }
+
+    /// Bind `name` in the innermost scope. Binding it again inside the same
+    /// scope reuses the local.
+    fn bind(&mut self, name: &str) -> String {
+        let scope = self.scopes.len() - 1;
+        if let Some((_, local)) = self.scopes[scope].iter().find(|(bound, _)| bound == name) {
+            return local.clone();
+        }
+        let local = self.allocate_local(name);
+        self.scopes[scope].push((name.to_string(), local.clone()));
+        local
+    }
+
+    /// Translate the forms of a block. Variables bound inside of it are only
+    /// visible until its end.
+    fn translate_block(
+        &mut self,
+        block: &mut Block,
+        forms: &[Sexp],
+        var: Option<&str>,
+    ) -> CompileResult<()> {
+        self.scopes.push(vec![]);
+        let result = self.translate_body(block, forms, var);
+        self.scopes.pop();
+        result
+    }
+
+    /// `(let name value)` and `(let pattern value)` bind variables for the rest
+    /// of the block, `(let [(pattern value)...] body...)` and
+    /// `(let (pattern value)... body)` for the body only. If `var` is given,
+    /// the value of the form is assigned to it.
+    fn translate_let(
+        &mut self,
+        block: &mut Block,
+        list: &[Sexp],
+        loc: &ByteSpan,
+        var: Option<&str>,
+    ) -> CompileResult<()> {
+        self.expect_operands(list, loc, 1, None)?;
 
-        for stmt in body.iter() {
-            self.translate_macro(hir.block_mut(), &stmt)?;
+        if let Some((bindings, body)) = let_bindings(list) {
+            self.scopes.push(vec![]);
+            let result = bindings.iter().try_for_each(|binding| {
+                let binding = match binding {
+                    Sexp::List(binding, _) if binding.len() == 2 => binding,
+                    _ => return Err(self.unexpected("`(pattern value)`", binding)),
+                };
+                let val = self.translate_value(block, &binding[1])?;
+                self.translate_pattern(block, &binding[0], val)?;
+                Ok(())
+            });
+            let result = result.and_then(|_| self.translate_body(block, body, var));
+            self.scopes.pop();
+            return result;
         }
 
+        self.expect_operands(list, loc, 2, Some(2))?;
+        let val = self.translate_value(block, &list[2])?;
+        let val = self.translate_pattern(block, &list[1], val)?;
+        if let Some(var) = var {
+            block.step(Assign::local(&Variable::from(var), val));
+        }
         Ok(())
     }
 
-    fn translate_macro(&self, block: &mut Block, ast: &Sexp) -> Result<(), String> {
-        let list = take_as!(&ast, Sexp::List)?;
-        let name = take_as!(&list[0], Sexp::Sym)?;
-        // TODO: avoid index errors here
+    /// Bind the names of `pattern` to parts of `val` and return the variable
+    /// holding the value. A list pattern matches items by position and a dict
+    /// pattern `{name key...}` reads the given keys.
+    fn translate_pattern(
+        &mut self,
+        block: &mut Block,
+        pattern: &Sexp,
+        val: Expr,
+    ) -> CompileResult<Expr> {
+        let items = match pattern {
+            Sexp::Sym(name, _) if self.toplevel && self.scopes.len() == 1 => {
+                let global = Global {
+                    key: name.to_string(),
+                    constant: false,
+                };
+                self.globals.insert(name.to_string(), global);
+                let var = Variable::from(name.as_ref());
+                block.step(Assign::global(&var, val));
+                return Ok(var.into());
+            }
+            Sexp::Sym(name, _) => {
+                let local = Variable::from(self.bind(name));
+                block.step(Assign::local(&local, val));
+                return Ok(local.into());
+            }
+            Sexp::List(items, _) => items,
+            _ => return Err(self.unexpected("variable or pattern", pattern)),
+        };
+        let dict = delimited(pattern, BRACES);
+        let items = dict
+            .or_else(|| delimited(pattern, BRACKETS))
+            .unwrap_or(items);
+
+        // evaluate the value only once
+        let var = Variable::from(self.temporary("value"));
+        block.step(Assign::local(&var, val));
+
+        if dict.is_some() {
+            if !items.len().is_multiple_of(2) {
+                return Err(self.unexpected("`{name key...}`", pattern));
+            }
+            for pair in items.chunks(2) {
+                let key = self.translate_value(block, &pair[1])?;
+                let val = Access::new(var.clone(), vec![key]);
+                self.translate_pattern(block, &pair[0], val.into())?;
+            }
+        } else {
+            for (i, item) in items.iter().enumerate() {
+                let val = Access::new(var.clone(), vec![Expr::from(i as i64)]);
+                self.translate_pattern(block, item, val.into())?;
+            }
+        }
+
+        Ok(var.into())
+    }
+
+    /// Declare a new local variable for a value computed by the transpiler.
+    fn temporary(&mut self, kind: &str) -> String {
+        self.temp_count += 1;
+        let var = format!("{{{}-{}}}", kind, self.temp_count);
+        self.declare_local(&var);
+        var
+    }
+
+    fn declare_local(&mut self, name: &str) {
+        if !self.locals.iter().any(|local| local == name) {
+            self.locals.push(name.to_string());
+        }
+    }
+
+    /// Track loops to decide whether `break` and `continue` leave a `try` block.
+    fn enter_loop(&mut self) {
+        if let Some(ctx) = &mut self.try_context {
+            ctx.loop_depth += 1;
+        }
+    }
+
+    fn leave_loop(&mut self) {
+        if let Some(ctx) = &mut self.try_context {
+            ctx.loop_depth -= 1;
+        }
+    }
+
+    /// Calls to local variables are resolved at runtime as they hold functions.
+    fn translate_call(&mut self, name: &str, args: &[Sexp], loc: &ByteSpan) -> CompileResult<Call> {
+        let mut args = self.translate_arguments(args)?;
+        if let Some(local) = self.local_name(name) {
+            args.insert(0, Variable::from(local).into());
+            return Ok(Call::with_args(runtime::CALL, args));
+        }
+        if self.private.iter().any(|private| private == name) {
+            return Ok(Call::with_args(format!("{}{}", PRIVATE_PREFIX, name), args));
+        }
+        let name = self.resolve_import(name, loc)?;
+        self.check_visibility(&name, loc)?;
+        Ok(Call::with_args(name, args))
+    }
+
+    fn translate_macro(&mut self, block: &mut Block, ast: &Sexp) -> CompileResult<()> {
+        // statements hoisted out of the expressions of the form run before it
+        let outer = self.hoisted.take();
+        let mut statement = Block::new();
+        let result = self.translate_statement(&mut statement, ast);
+        if let Some(hoisted) = std::mem::replace(&mut self.hoisted, outer) {
+            block.extend(hoisted);
+        }
+        block.extend(statement);
+        result
+    }
+
+    fn translate_statement(&mut self, block: &mut Block, ast: &Sexp) -> CompileResult<()> {
+        let (name, list) = self.take_form(ast)?;
+        let loc = ast.get_loc();
         let rest = &list[1..];
 
-        match name.as_ref() {
-            "break" => block.step(Break::new()),
-            "continue" => block.step(Continue::new()),
+        if builtin(name).is_none() {
+            let call = self.translate_call(name, rest, loc)?;
+            block.step(call);
+            return Ok(());
+        }
+
+        match name {
+            "break" => self.translate_jump(block, list, loc, Jump::Break)?,
+            "continue" => self.translate_jump(block, list, loc, Jump::Continue)?,
             "do" => {
                 for step in rest.iter() {
                     self.translate_macro(block, step)?;
                 }
             }
             "foreach" => {
-                let head = take_as!(&rest[0], Sexp::List)?;
-                assert_eq!(2, head.len());
+                let (label, rest) = self.take_label(rest);
+                if rest.is_empty() {
+                    return Err(self.unexpected("`(collection item)`", ast));
+                }
+                let head = take_as!(self, &rest[0], Sexp::List)?;
+                if head.len() != 2 {
+                    return Err(self.unexpected("`(collection item)`", &rest[0]));
+                }
 
                 let collection = self.translate_expr(&head[0])?;
-                let item = take_as!(&head[1], Sexp::Sym)?;
-                let item = item.to_string();
+                let item = take_as!(self, &head[1], Sexp::Sym)?;
+                let local = self.allocate_local(item);
 
-                let repeat = block.repeat_iterating(Iter::create(collection), item);
-                for step in rest[1..].iter() {
-                    self.translate_macro(repeat.block_mut(), step)?;
-                }
+                self.enter_labeled_loop(block, label);
+                let repeat = block.repeat_iterating(Iter::create(collection), local.as_str());
+                repeat.step(Interrupt::new(runtime::CHECKPOINT));
+                // the item is only visible inside the body
+                self.scopes.push(vec![(item.to_string(), local)]);
+                let result = self.translate_body(repeat.block_mut(), &rest[1..], None);
+                self.scopes.pop();
+                result?;
+                self.leave_labeled_loop(block);
+            }
+            "case" => {
+                self.expect_operands(list, loc, 1, None)?;
+                self.translate_case(block, rest, None)?;
             }
+            "cond" => self.translate_cond(block, rest, None)?,
             "if" => {
+                self.expect_operands(list, loc, 2, Some(3))?;
                 let condition = self.translate_expr(&rest[0])?;
                 let branch = block.branch();
-                self.translate_macro(branch.add_condition(condition), &rest[1])?;
+                self.translate_block(branch.add_condition(condition), &rest[1..2], None)?;
                 if rest.len() == 3 {
-                    self.translate_macro(branch.default_condition(), &rest[2])?;
+                    self.translate_block(branch.default_condition(), &rest[2..], None)?;
                 }
             }
             "import" => {
-                let name = take_as!(&rest[0], Sexp::Sym)?;
-                block.step(Include::import(name.as_ref()));
+                let import = self.parse_import(list, loc)?;
+                block.step(Include::import(import.module.as_str()));
+                self.add_import(import);
             }
             "import-global" => {
-                let name = take_as!(&rest[0], Sexp::Sym)?;
+                self.expect_operands(list, loc, 1, Some(1))?;
+                let name = take_as!(self, &rest[0], Sexp::Sym)?;
                 block.step(Include::import_global(name.as_ref()));
             }
-            "let" => {
-                assert_eq!(2, rest.len());
-                let name = take_as!(&rest[0], Sexp::Sym)?;
-                let name = Variable::from(name.to_string());
-                let val = self.translate_expr(&rest[1])?;
-                block.step(Assign::local(&name, val));
-            }
+            "let" => self.translate_let(block, list, loc, None)?,
             "loop" => {
+                let (label, rest) = self.take_label(rest);
+                self.enter_labeled_loop(block, label);
+                let mut body = Block::new();
+                self.translate_block(&mut body, rest, None)?;
+
                 let repeat = block.repeat();
-                for item in rest.iter() {
-                    self.translate_macro(repeat.block_mut(), item)?;
+                repeat.step(Interrupt::new(runtime::CHECKPOINT));
+                // the optimizer of lovm2 only finds the code following a loop
+                // through its breaks and drops it otherwise. a break behind a
+                // condition it cannot evaluate keeps it without ever running.
+                if !self.loops.last().is_some_and(|ctx| ctx.breaks) {
+                    repeat
+                        .branch()
+                        .add_condition(Conv::to_bool(Value::Bool(false)).into())
+                        .step(Break::new());
                 }
+                repeat.block_mut().extend(body);
+                self.leave_labeled_loop(block);
+            }
+            "raise" => {
+                self.expect_operands(list, loc, 1, Some(1))?;
+                let val = self.translate_expr(&rest[0])?;
+                block.step(Call::with_args(runtime::RAISE, vec![val]));
             }
             "ret" => {
-                assert!(rest.len() <= 1);
-                let inx = if rest.is_empty() {
-                    Return::nil()
-                } else {
-                    let val = self.translate_expr(&rest[0])?;
-                    Return::value(val)
+                self.expect_operands(list, loc, 0, Some(1))?;
+                let val = match rest.first() {
+                    Some(val) => self.translate_expr(val)?,
+                    _ => Value::Nil.into(),
+                };
+                self.translate_return(block, val);
+            }
+            "set-global" => {
+                self.expect_operands(list, loc, 2, Some(2))?;
+                let name = take_as!(self, &rest[0], Sexp::Sym)?;
+                let key = match self.globals.get(name.as_ref()) {
+                    Some(global) if !global.constant => global.key.clone(),
+                    Some(_) => {
+                        return Err(CompileError::Global {
+                            msg: format!("cannot assign to constant `{}`", name),
+                            loc: self.locate(rest[0].get_loc()),
+                        })
+                    }
+                    None => {
+                        return Err(CompileError::Global {
+                            msg: format!("global variable `{}` is not declared", name),
+                            loc: self.locate(rest[0].get_loc()),
+                        })
+                    }
+                };
+                let val = self.translate_expr(&rest[1])?;
+                block.step(Assign::global(&Variable::from(key), val));
+            }
+            "set!" => {
+                self.expect_operands(list, loc, 2, Some(2))?;
+                let name = take_as!(self, &rest[0], Sexp::Sym)?;
+                let local = match self.local_name(name) {
+                    Some(local) if self.captures.iter().any(|c| c == local) => {
+                        return Err(CompileError::Unbound {
+                            msg: format!(
+                                "`{}` is captured by value and cannot be assigned inside `fn`",
+                                name
+                            ),
+                            loc: self.locate(rest[0].get_loc()),
+                        });
+                    }
+                    Some(local) => local.to_string(),
+                    // variables of a script are kept as globals
+                    _ if self.script
+                        && matches!(self.globals.get(name.as_ref()), Some(g) if !g.constant) =>
+                    {
+                        let val = self.translate_expr(&rest[1])?;
+                        block.step(Assign::global(&Variable::from(name.as_ref()), val));
+                        return Ok(());
+                    }
+                    _ => {
+                        let msg = match self.globals.contains_key(name.as_ref()) {
+                            true => format!("`{}` is a global, use `set-global`", name),
+                            _ => format!("variable `{}` is not bound", name),
+                        };
+                        return Err(CompileError::Unbound {
+                            msg,
+                            loc: self.locate(rest[0].get_loc()),
+                        });
+                    }
                 };
-                block.step(inx);
+                let val = self.translate_expr(&rest[1])?;
+                block.step(Assign::local(&Variable::from(local), val));
+            }
+            "try" => self.translate_try(block, list, loc)?,
+            "while" => {
+                let (label, rest) = self.take_label(rest);
+                if rest.is_empty() {
+                    return Err(self.unexpected("condition", ast));
+                }
+                self.enter_labeled_loop(block, label);
+                let repeat = block.repeat();
+                // the condition can require statements that run on every iteration
+                let condition = self.translate_value(repeat.block_mut(), &rest[0])?;
+                // `not` inverts the bits of integers, only booleans can be negated
+                repeat
+                    .branch()
+                    .add_condition(Expr::not(Conv::to_bool(condition)))
+                    .step(Break::new());
+                repeat.step(Interrupt::new(runtime::CHECKPOINT));
+                self.translate_block(repeat.block_mut(), &rest[1..], None)?;
+                self.leave_labeled_loop(block);
+            }
+            "when" | "unless" => {
+                self.expect_operands(list, loc, 1, None)?;
+                let mut condition = self.translate_expr(&rest[0])?;
+                if name == "unless" {
+                    condition = Expr::not(Conv::to_bool(condition));
+                }
+                let branch = block.branch();
+                self.translate_block(branch.add_condition(condition), &rest[1..], None)?;
+            }
+            _ => {
+                // the value of other builtins is not used
+                let val = self.translate_expr(ast)?;
+                self.declare_local(DISCARDED);
+                block.step(Assign::local(&Variable::from(DISCARDED), val));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Translate an expression and put the statements it requires into `block`.
+    fn translate_value(&mut self, block: &mut Block, sexp: &Sexp) -> CompileResult<Expr> {
+        let outer = self.hoisted.take();
+        let expr = self.translate_expr(sexp);
+        if let Some(hoisted) = std::mem::replace(&mut self.hoisted, outer) {
+            block.extend(hoisted);
+        }
+        expr
+    }
+
+    /// Translate `sexp` so that its value is assigned to the local `var`. The
+    /// branches of `if`, `cond`, `case`, `do` and `let` assign it directly, forms
+    /// without a value leave it untouched.
+    fn translate_into(&mut self, block: &mut Block, var: &str, sexp: &Sexp) -> CompileResult<()> {
+        match sexp {
+            Sexp::List(list, loc) if is_form(sexp, "if") => {
+                self.expect_operands(list, loc, 2, Some(3))?;
+                let condition = self.translate_value(block, &list[1])?;
+                let branch = block.branch();
+                self.translate_block(branch.add_condition(condition), &list[2..3], Some(var))?;
+                if list.len() == 4 {
+                    self.translate_block(branch.default_condition(), &list[3..], Some(var))?;
+                }
+                Ok(())
+            }
+            Sexp::List(list, _) if is_form(sexp, "cond") => {
+                self.translate_cond(block, &list[1..], Some(var))
+            }
+            Sexp::List(list, loc) if is_form(sexp, "case") => {
+                self.expect_operands(list, loc, 1, None)?;
+                self.translate_case(block, &list[1..], Some(var))
+            }
+            Sexp::List(list, _) if is_form(sexp, "do") => {
+                self.translate_body(block, &list[1..], Some(var))
             }
+            Sexp::List(list, loc) if is_form(sexp, "let") => {
+                self.translate_let(block, list, loc, Some(var))
+            }
+            _ if self.is_statement(sexp) => self.translate_macro(block, sexp),
             _ => {
-                let args = self.to_expr_vec(rest)?;
-                block.step(Call::with_args(name.as_ref(), args));
+                let val = self.translate_value(block, sexp)?;
+                block.step(Assign::local(&Variable::from(var), val));
+                Ok(())
             }
         }
+    }
 
+    /// Translate the forms of a body. If `var` is given, the value of the
+    /// last form is assigned to it.
+    fn translate_body(
+        &mut self,
+        block: &mut Block,
+        body: &[Sexp],
+        var: Option<&str>,
+    ) -> CompileResult<()> {
+        for (i, form) in body.iter().enumerate() {
+            match var {
+                Some(var) if i + 1 == body.len() => self.translate_into(block, var, form)?,
+                _ => self.translate_macro(block, form)?,
+            }
+        }
         Ok(())
     }
 
-    fn translate_expr(&self, sexp: &Sexp) -> Result<Expr, String> {
+    fn translate_expr(&mut self, sexp: &Sexp) -> CompileResult<Expr> {
         match sexp {
-            Sexp::Sym(name, _) => Ok(Expr::from(Variable::from(name.to_string()))),
+            Sexp::Sym(name, loc) => self.translate_variable(name, loc),
             Sexp::Str(s, _) => Ok(Expr::from(s.as_ref())),
             Sexp::Char(c, _) => Ok(Expr::from(format!("{}", c))),
             Sexp::Int(n, _) => Ok(Expr::from(*n)),
             Sexp::Float(n, _) => Ok(Expr::from(*n)),
-            Sexp::List(list, _) => self.translate_expr_macro(list),
+            Sexp::List(_, _) => self.translate_expr_macro(sexp),
+        }
+    }
+
+    /// Globals of the module are read from their key and constants of imported
+    /// modules by calling the function returning them.
+    fn translate_variable(&self, name: &str, loc: &ByteSpan) -> CompileResult<Expr> {
+        if let Some(local) = self.local_name(name) {
+            return Ok(Variable::from(local).into());
+        }
+        if let Some(global) = self.globals.get(name) {
+            return Ok(Variable::from(global.key.clone()).into());
+        }
+
+        let resolved = self.resolve_import(name, loc)?;
+        let is_constant = self
+            .constants
+            .iter()
+            .any(|(module, constant)| namespaced(module, constant, self.sep()) == resolved);
+        match is_constant {
+            true => Ok(Call::with_args(resolved, vec![]).into()),
+            _ => Ok(Variable::from(name).into()),
         }
     }
 
-    fn translate_expr_macro(&self, list: &[Sexp]) -> Result<Expr, String> {
-        let name = take_as!(&list[0], Sexp::Sym)?;
+    fn translate_expr_macro(&mut self, ast: &Sexp) -> CompileResult<Expr> {
+        let (name, list) = self.take_form(ast)?;
+        let loc = ast.get_loc();
+
+        if matches!(builtin(name), None | Some(Builtin::Statement)) {
+            let call = self.translate_call(name, &list[1..], loc)?;
+            return Ok(Expr::from(call));
+        }
+
+        if let Some(op) = self.maps_to_operator(name) {
+            // `(- x)` negates its only operand
+            if op == Operator2::Sub && list.len() == 2 {
+                let expr = self.translate_expr(&list[1])?;
+                return Ok(Expr::from_opn(op, vec![Value::Int(0).into(), expr]));
+            }
 
-        if let Some(op) = self.maps_to_operator(name.as_ref()) {
-            let mut rest = self.to_expr_vec(&list[1..])?;
+            self.expect_operands(list, loc, 2, None)?;
+            let mut rest = self.translate_exprs(&list[1..])?;
 
             // automatically turn first operand into float to
             // avoid information loss on integer division
@@ -223,12 +2426,12 @@ impl Transpiler {
 
             Ok(Expr::from_opn(op, rest))
         } else {
-            match name.as_ref() {
+            match name {
                 "bool" | "float" | "int" | "str" => {
-                    assert_eq!(2, list.len());
+                    self.expect_operands(list, loc, 1, Some(1))?;
                     let expr = self.translate_expr(&list[1])?;
 
-                    Ok(match name.as_ref() {
+                    Ok(match name {
                         "bool" => Conv::to_bool(expr),
                         "float" => Conv::to_float(expr),
                         "int" => Conv::to_integer(expr),
@@ -240,23 +2443,24 @@ impl Transpiler {
                 "dict" => {
                     let mut dict = Initialize::new(Value::dict().into());
 
+                    let mut items = vec![];
                     for tuple in &list[1..] {
                         match tuple {
-                            Sexp::List(tuple, _) => {
-                                assert_eq!(2, tuple.len());
-                                let mut kv = self.to_expr_vec(tuple)?;
-                                let (key, value) = (kv.remove(0), kv.remove(0));
-                                dict.add_by_key(key, value);
-                            }
-                            _ => return Err("expected key-value tuple".to_string()),
+                            Sexp::List(kv, _) if kv.len() == 2 => items.extend(kv.iter().cloned()),
+                            _ => return Err(self.unexpected("`(key value)`", tuple)),
                         }
                     }
 
+                    let mut items = self.translate_exprs(&items)?.into_iter();
+                    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                        dict.add_by_key(key, value);
+                    }
+
                     Ok(dict.into())
                 }
                 "list" => {
                     let mut ls = Initialize::new(Value::list().into());
-                    let rest = self.to_expr_vec(&list[1..])?;
+                    let rest = self.translate_exprs(&list[1..])?;
 
                     for item in rest {
                         ls.add(item);
@@ -265,33 +2469,133 @@ impl Transpiler {
                     Ok(ls.into())
                 }
                 "not" => {
-                    let rest = self.to_expr_vec(&list[1..])?;
-                    assert_eq!(1, rest.len());
-                    Ok(Expr::not(rest[0].clone()))
+                    self.expect_operands(list, loc, 1, Some(1))?;
+                    let expr = self.translate_expr(&list[1])?;
+                    Ok(Expr::not(expr))
                 }
                 "range" => {
-                    let rest = self.to_expr_vec(&list[1..])?;
+                    self.expect_operands(list, loc, 1, Some(2))?;
+                    let rest = self.translate_exprs(&list[1..])?;
                     let (from, to): (Expr, Expr) = match rest.as_slice() {
                         [first] => (Value::Nil.into(), first.clone()),
                         [first, second] => (first.clone(), second.clone()),
-                        _ => unimplemented!(),
+                        _ => unreachable!(),
                     };
 
                     Ok(Iter::create_ranged(from, to).into())
                 }
+                "fn" | "lambda" => self.translate_lambda(list, loc),
+                "if" | "cond" | "case" | "do" | "let" => {
+                    let var = self.temporary("value");
+                    let mut block = self.hoisted.take().unwrap_or_else(Block::new);
+                    block.step(Assign::local(&Variable::from(var.as_str()), Value::Nil));
+                    let result = self.translate_into(&mut block, &var, ast);
+                    self.hoisted = Some(block);
+                    result?;
+                    Ok(Variable::from(var).into())
+                }
+                "quote" => {
+                    self.expect_operands(list, loc, 1, Some(1))?;
+                    Ok(quoted(&list[1]))
+                }
+                "quasiquote" | "unquote" | "unquote-splicing" => {
+                    let msg = format!("`{}` can only be used inside of `defmacro`", name);
+                    Err(self.macro_error(msg, loc))
+                }
+                _ => unreachable!("builtin macro `{}` is not translated", name),
+            }
+        }
+    }
+
+    /// Translate the arguments of a call. Arguments given as `:name value` are
+    /// passed together as last argument. All values are evaluated in the order
+    /// they appear in the source.
+    fn translate_arguments(&mut self, args: &[Sexp]) -> CompileResult<Vec<Expr>> {
+        let mut values = vec![];
+        let mut keys = vec![];
+        let mut items = args.iter();
+
+        while let Some(arg) = items.next() {
+            match arg {
+                Sexp::Sym(name, _) if name.len() > 1 && name.starts_with(':') => match items.next()
+                {
+                    Some(val) => {
+                        values.push(val.clone());
+                        keys.push(Some(&name[1..]));
+                    }
+                    _ => return Err(self.unexpected("value after keyword", arg)),
+                },
                 _ => {
-                    let rest = self.to_expr_vec(&list[1..])?;
-                    let call = Call::with_args(name.as_ref(), rest);
-                    Ok(Expr::from(call))
+                    values.push(arg.clone());
+                    keys.push(None);
+                }
+            }
+        }
+
+        if keys.iter().all(Option::is_none) {
+            return self.translate_exprs(args);
+        }
+
+        let mut values = self.translate_exprs(&values)?;
+
+        // keyword values are passed last, so they have to be stored first if a
+        // positional argument follows them
+        let first_keyword = keys.iter().position(Option::is_some).unwrap();
+        if keys[first_keyword..].iter().any(Option::is_none) {
+            for val in values.iter_mut() {
+                if let Expr::Value { .. } = val {
+                    continue;
+                }
+                let var = Variable::from(self.temporary("value"));
+                let val = std::mem::replace(val, var.clone().into());
+                self.hoist(Assign::local(&var, val));
+            }
+        }
+
+        let mut args = vec![];
+        let mut pairs = vec![];
+        for (key, val) in keys.into_iter().zip(values) {
+            match key {
+                Some(name) => {
+                    pairs.push(Expr::from(name));
+                    pairs.push(val);
                 }
+                _ => args.push(val),
             }
         }
+        args.push(Call::with_args(runtime::KEYWORDS, pairs).into());
+
+        Ok(args)
+    }
+
+    fn hoist(&mut self, step: Assign) {
+        self.hoisted.get_or_insert_with(Block::new).step(step);
     }
 
-    fn to_expr_vec(&self, list: &[Sexp]) -> Result<Vec<Expr>, String> {
+    /// Operands are evaluated from left to right. If an operand requires
+    /// statements, the operands before it are stored in locals first.
+    fn translate_exprs(&mut self, list: &[Sexp]) -> CompileResult<Vec<Expr>> {
         let mut rest = vec![];
+        let mut stored = 0;
         for item in list.iter() {
-            rest.push(self.translate_expr(item)?);
+            let outer = self.hoisted.take();
+            let expr = self.translate_expr(item);
+            let hoisted = std::mem::replace(&mut self.hoisted, outer);
+
+            if let Some(hoisted) = hoisted {
+                for prev in rest[stored..].iter_mut() {
+                    if let Expr::Value { .. } = prev {
+                        continue;
+                    }
+                    let var = Variable::from(self.temporary("value"));
+                    let val = std::mem::replace(prev, var.clone().into());
+                    self.hoist(Assign::local(&var, val));
+                }
+                stored = rest.len();
+                self.hoisted.get_or_insert_with(Block::new).extend(hoisted);
+            }
+
+            rest.push(expr?);
         }
         Ok(rest)
     }
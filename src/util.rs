@@ -1,19 +1,139 @@
 pub fn create_lol_module(
     name: &str,
     src: &str,
-) -> lovm2::prelude::Lovm2Result<lovm2::module::Module> {
+) -> Result<lovm2::module::Module, crate::Diagnostic> {
     let mut trans = crate::Transpiler::new();
     let meta: lovm2::prelude::ModuleMeta = name.to_string().into();
-    let module: lovm2::module::Module = trans.build(meta, src).unwrap();
-    Ok(module)
+    trans
+        .build(meta, src)
+        .map_err(|e| crate::Diagnostic::from_compile_error(&e, Some(src)))
 }
 
-pub fn create_lol_runtime(name: &str, src: &str) -> crate::Interpreter {
+/// Create an interpreter with the module compiled from `src` imported globally.
+pub fn create_lol_runtime(name: &str, src: &str) -> Result<crate::Interpreter, crate::Diagnostic> {
     let mut int = crate::Interpreter::new();
-    let module = crate::create_lol_module(name, src).unwrap();
+    let module = create_lol_module(name, src)?;
 
-    println!("{}", module);
+    int.load_global(module)
+        .map_err(|e| int.diagnose(&e, None, Some(src)))?;
+    Ok(int)
+}
+
+/// Add `co` to `vm` as `name`, replacing a function of the same name.
+pub(crate) fn replace_function<T>(
+    vm: &mut lovm2::vm::Vm,
+    name: T,
+    co: lovm2::code::CallableRef,
+) -> lovm2::prelude::Lovm2Result<()>
+where
+    T: Into<lovm2::Variable>,
+{
+    // the vm overwrites the previous function but reports a conflict anyway
+    match vm.add_function(name, co) {
+        Err(e) if e.ty == lovm2::prelude::Lovm2ErrorTy::ImportConflict => Ok(()),
+        result => result,
+    }
+}
+
+/// Hash `data` using fnv-1a. This is not meant for security purposes.
+pub fn fnv1a<T>(data: T) -> u64
+where
+    T: IntoIterator<Item = u8>,
+{
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in data {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Separates the components of nested module names like `net.http`.
+pub const MODULE_SEPARATOR: char = '.';
+/// Default separator of module and function inside namespaced names like
+/// `net-http-get`.
+pub const NAMESPACE_SEPARATOR: &str = "-";
+
+/// Name under which `name` of `module` is reachable when imported namespaced.
+/// The components of nested module names are joined using `separator` as well.
+pub fn namespaced(module: &str, name: &str, separator: &str) -> String {
+    let module = module.replace(MODULE_SEPARATOR, separator);
+    format!("{}{}{}", module, separator, name)
+}
+
+/// Derive the module name from a path relative to the source directory e.g.
+/// `net.http` for `net/http.lol`.
+pub fn module_name<T>(relpath: T) -> String
+where
+    T: AsRef<std::path::Path>,
+{
+    relpath
+        .as_ref()
+        .with_extension("")
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy().to_string())
+        .collect::<Vec<_>>()
+        .join(&MODULE_SEPARATOR.to_string())
+}
+
+/// Path of module `name` relative to a source directory e.g. `net/http` for `net.http`.
+pub(crate) fn module_path(name: &str) -> std::path::PathBuf {
+    name.split(MODULE_SEPARATOR).collect()
+}
+
+/// Files that could contain module `name` inside `dir`, in order of preference.
+pub fn module_candidates<T>(name: &str, dir: T, extensions: &[&str]) -> Vec<std::path::PathBuf>
+where
+    T: AsRef<std::path::Path>,
+{
+    let relpath = module_path(name);
+    extensions
+        .iter()
+        .map(|ext| dir.as_ref().join(&relpath).with_extension(ext))
+        .collect()
+}
+
+/// Directory that contains the source root of module `name` located inside
+/// `dir` e.g. `src` for `net.http` in `src/net`.
+pub fn source_root<'a>(dir: &'a std::path::Path, name: &str) -> Option<&'a std::path::Path> {
+    dir.ancestors().nth(name.matches(MODULE_SEPARATOR).count())
+}
+
+/// Files that could contain module `name` when imported from a module inside
+/// `dir`. Nested modules are resolved relative to the source root, which is why
+/// the parents of `dir` are searched as well up to the directory containing
+/// `lol.toml` or otherwise `root`. Without either only `dir` is searched.
+pub fn relative_module_candidates<T>(
+    name: &str,
+    dir: T,
+    root: Option<&std::path::Path>,
+    extensions: &[&str],
+) -> Vec<std::path::PathBuf>
+where
+    T: AsRef<std::path::Path>,
+{
+    let dir = dir.as_ref();
+    let root = dir
+        .ancestors()
+        .find(|dir| dir.join(crate::manifest::MANIFEST_FILE).exists())
+        .or_else(|| root.filter(|root| dir.starts_with(root)))
+        .unwrap_or(dir);
+
+    let mut candidates = vec![];
+    for dir in dir.ancestors() {
+        candidates.extend(module_candidates(name, dir, extensions));
+
+        if dir == root {
+            break;
+        }
+    }
+
+    candidates
+}
 
-    int.load_global(module).unwrap();
-    int
+/// Directories listed in the environment variable `LOL_PATH`.
+pub fn lol_path() -> Vec<std::path::PathBuf> {
+    std::env::var_os(crate::interpreter::LOL_PATH)
+        .map(|paths| std::env::split_paths(&paths).collect())
+        .unwrap_or_default()
 }
@@ -1,11 +1,74 @@
 #![cfg(test)]
 
-use lol::{create_lol_module, create_lol_runtime, Interpreter};
+use lol::{create_lol_module, Interpreter, InterpreterBuilder};
 use lovm2::prelude::*;
 
+/// Build an interpreter caching compiled modules in a temporary directory
+/// instead of the cache of the user.
+fn build(builder: InterpreterBuilder) -> Interpreter {
+    let mut int = builder.build();
+    int.set_cache_dir(Some(
+        std::env::temp_dir().join(format!("lol-test-cache-{}", std::process::id())),
+    ));
+    int
+}
+
+/// Directory inside the temporary directory that is removed again when the
+/// test ends, even if it fails.
+struct TempDir(std::path::PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("lol-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+impl std::ops::Deref for TempDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AsRef<std::path::Path> for TempDir {
+    fn as_ref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn interpreter() -> Interpreter {
+    build(Interpreter::builder())
+}
+
+/// Like `create_lol_runtime` but using the temporary cache.
+fn runtime(name: &str, src: &str) -> Result<Interpreter, lol::Diagnostic> {
+    let mut int = interpreter();
+    let module = create_lol_module(name, src)?;
+    int.load_global(module)
+        .map_err(|e| int.diagnose(&e, None, Some(src)))?;
+    Ok(int)
+}
+
+/// Interpreter searching imported modules inside `dir`.
+fn interpreter_with(dir: &std::path::Path) -> Interpreter {
+    let mut int = interpreter();
+    int.add_search_path(dir);
+    int
+}
+
 #[test]
 fn arithmetic() {
-    let mut int = create_lol_runtime(
+    let mut int = runtime(
         "main",
         "
         (def add (a b)
@@ -18,8 +81,13 @@ fn arithmetic() {
             (ret (/ a b)))
         (def rem (a b)
             (ret (% a b)))
+        (def neg (a)
+            (ret (- a)))
+        (def invert (a)
+            (ret (not a)))
         ",
-    );
+    )
+    .unwrap();
 
     let add = int.call("add", &[1, 2]).unwrap();
     let sub = int.call("sub", &[1, 2]).unwrap();
@@ -31,11 +99,14 @@ fn arithmetic() {
     assert_eq!(Value::from(2), mul);
     assert_eq!(Value::from(0.5), div);
     assert_eq!(Value::from(1), rem);
+    assert_eq!(Value::from(-2), int.call("neg", &[2]).unwrap());
+    assert_eq!(Value::from(-0.5), int.call("neg", &[0.5]).unwrap());
+    assert_eq!(Value::from(false), int.call("invert", &[true]).unwrap());
 }
 
 #[test]
 fn recursive_faculty() {
-    let mut int = create_lol_runtime(
+    let mut int = runtime(
         "main",
         "
         (def fac (x) 
@@ -43,7 +114,8 @@ fn recursive_faculty() {
                 (ret (* x (fac (- x 1))))
                 (ret 1)))
         ",
-    );
+    )
+    .unwrap();
 
     assert_eq!(Value::from(1), int.call("fac", &[1]).unwrap());
     assert_eq!(Value::from(2), int.call("fac", &[2]).unwrap());
@@ -53,7 +125,7 @@ fn recursive_faculty() {
 
 #[test]
 fn looping() {
-    let mut int = create_lol_runtime(
+    let mut int = runtime(
         "loops",
         "
         (def looping (n)
@@ -64,13 +136,14 @@ fn looping() {
                     (break))
                 (if (eq (% i 2) 0)
                     (do
-                        (let i (+ i 1))
+                        (set! i (+ i 1))
                         (continue)))
-                (let r (* r i))
-                (let i (+ i 1)))
+                (set! r (* r i))
+                (set! i (+ i 1)))
             (ret r))
         ",
-    );
+    )
+    .unwrap();
 
     assert_eq!(Value::from(1), int.call("looping", &[1]).unwrap());
     assert_eq!(Value::from(1), int.call("looping", &[2]).unwrap());
@@ -79,7 +152,7 @@ fn looping() {
 
 #[test]
 fn import_vice_versa() {
-    let mut int = Interpreter::new();
+    let mut int = interpreter();
     let a = create_lol_module(
         "a",
         "
@@ -106,7 +179,7 @@ fn import_vice_versa() {
 
 #[test]
 fn create_complex_types() {
-    let mut int = Interpreter::new();
+    let mut int = interpreter();
     let main = create_lol_module(
         "main",
         r#"
@@ -135,14 +208,14 @@ fn create_complex_types() {
 
 #[test]
 fn foreach() {
-    let mut int = Interpreter::new();
+    let mut int = interpreter();
     let main = create_lol_module(
         "main",
         r#"
     (def sum (n)
         (let res 0)
         (foreach ((range 1 (+ n 1)) i)
-            (let res (+ res i)))
+            (set! res (+ res i)))
         (ret res))
         "#,
     )
@@ -157,7 +230,7 @@ fn foreach() {
 
 #[test]
 fn converting() {
-    let mut int = Interpreter::new();
+    let mut int = interpreter();
     let main = create_lol_module(
         "main",
         r#"
@@ -180,3 +253,1634 @@ fn converting() {
     assert_eq!(Value::from(1), int.call("as-int", &[true]).unwrap());
     assert_eq!(Value::from("4"), int.call("as-str", &[4]).unwrap());
 }
+
+#[test]
+fn closures() {
+    let mut int = interpreter();
+    let main = create_lol_module(
+        "main",
+        "
+        (def apply (f x)
+            (ret (f x)))
+        (def square (x)
+            (let f (fn (n) (ret (* n n))))
+            (ret (apply f x)))
+        (def adder (n)
+            (ret (fn (x) (ret (+ x n)))))
+        (def add-all (n xs)
+            (ret (map xs (adder n))))
+        (def counter (start)
+            (let step (fn (x) (ret (+ x 1))))
+            (ret (fn () (ret (fn (x) (ret (step (+ start x))))))))
+        (def nested (start)
+            (let make (counter start))
+            (let inc (make))
+            (ret (inc 10)))
+        (def sum-adders (n)
+            (let total 0)
+            (foreach ((range n) i)
+                (let add (adder i))
+                (set! total (+ total (add 1)))
+                (set! total (+ total (get (map (list 1) add) 0))))
+            (ret total))
+        ",
+    )
+    .unwrap();
+    int.load(main).unwrap();
+
+    assert_eq!(Value::from(9), int.call("main-square", &[3]).unwrap());
+    assert_eq!(
+        Value::from(vec![Value::from(3), Value::from(4)]),
+        int.call("main-add-all", &[Value::from(2), Value::from(vec![1, 2])])
+            .unwrap()
+    );
+    assert_eq!(Value::from(16), int.call("main-nested", &[5]).unwrap());
+    assert_eq!(Value::from(12), int.call("main-sum-adders", &[3]).unwrap());
+
+    // closures are values, builtins reuse the name they are bound to
+    let ctx = int.context_mut();
+    assert!(ctx.lookup_code_object(&"{closure-1}".into()).is_ok());
+    assert!(ctx.lookup_code_object(&"{closure-2}".into()).is_err());
+
+    // captured variables hold copies and cannot be assigned
+    let e = create_lol_module(
+        "main",
+        "(def counter () (let n 0) (ret (fn () (set! n (+ n 1)) n)))",
+    )
+    .unwrap_err();
+    assert!(e.msg.contains("`n` is captured by value"), "{}", e.msg);
+    let shadowed = create_lol_module(
+        "main",
+        "(def counter () (let n 0) (ret (fn () (let n 1) (set! n (+ n 1)) n)))",
+    );
+    assert!(shadowed.is_ok());
+}
+
+#[test]
+fn macros() {
+    use lol::{CompileError, Transpiler};
+
+    let mut int = runtime(
+        "main",
+        "
+        (defmacro if-not (cond &rest body)
+            `(if (not ,cond) (do ,@body)))
+        (defmacro swap (a b)
+            `(do (let tmp ,a) (let ,a ,b) (let ,b tmp)))
+        (def sign (x)
+            (if-not (lt x 0) (ret 1))
+            (ret 0))
+        (def swapped (tmp other)
+            (swap tmp other)
+            (ret (list tmp other)))
+        (def quoted ()
+            (ret '(a 1 (b))))
+        (defmacro doubled (x)
+            `(let [(d (* 2 ,x))] d))
+        (defmacro name-of (x)
+            `(let [({n \"name\"} ,x)] n))
+        (def delimiters ()
+            (list (doubled 2) (name-of (dict (\"name\" \"lol\")))))
+        ",
+    )
+    .unwrap();
+
+    assert_eq!(Value::from(1), int.call("sign", &[2]).unwrap());
+    assert_eq!(Value::from(0), int.call("sign", &[-2]).unwrap());
+    // `tmp` inside the macro does not clash with the parameter
+    assert_eq!(
+        Value::from(vec![2, 1]),
+        int.call("swapped", &[1, 2]).unwrap()
+    );
+    assert_eq!(
+        Value::from(vec![
+            Value::from("a"),
+            Value::from(1),
+            Value::from(vec![Value::from("b")])
+        ]),
+        int.call("quoted", &[] as &[Value]).unwrap()
+    );
+    // brackets and braces are kept in the expansion of templates
+    assert_eq!(
+        Value::from(vec![Value::from(4), Value::from("lol")]),
+        int.call("delimiters", &[] as &[Value]).unwrap()
+    );
+
+    let dir = TempDir::new("macros");
+    std::fs::write(
+        dir.join("util.lol"),
+        "(defmacro twice (x) `(+ ,x ,x)) (defmacro dec (x) `(let [(d (- ,x 21))] d))",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("main.lol"),
+        "(import util) (def main () (ret (util-twice (util-dec 42))))",
+    )
+    .unwrap();
+
+    let util = Transpiler::new()
+        .build_from_path(dir.join("util.lol"))
+        .unwrap();
+    let main = Transpiler::new()
+        .build_from_path(dir.join("main.lol"))
+        .unwrap();
+    let mut int = interpreter();
+    int.load(util).unwrap();
+    int.load_global(main).unwrap();
+    assert_eq!(Value::from(42), int.call("main", &[] as &[Value]).unwrap());
+
+    let build = |src| Transpiler::new().build("main".to_string().into(), src);
+    assert!(matches!(
+        build("(defmacro loop-forever () `(loop-forever)) (def f () (loop-forever))"),
+        Err(CompileError::Macro { .. })
+    ));
+    assert!(matches!(
+        build("(defmacro one (x) `(ret ,x)) (def f () (one))"),
+        Err(CompileError::OperandCount { .. })
+    ));
+    assert!(matches!(
+        build("(def f (x) (ret `(,x)))"),
+        Err(CompileError::Macro { .. })
+    ));
+    assert!(matches!(
+        build("(defmacro m () `(let [()] 1)) (def f () (m))"),
+        Err(CompileError::UnexpectedForm { .. })
+    ));
+}
+
+#[test]
+fn exceptions() {
+    let mut int = runtime(
+        "main",
+        "
+        (def parse (s)
+            (let n 0)
+            (let done 0)
+            (try
+                (set! n (int s))
+                (catch e
+                    (set! n e))
+                (finally
+                    (set! done 1)))
+            (ret (list n done)))
+        (def checked (x)
+            (if (lt x 0)
+                (raise \"negative\"))
+            (ret x))
+        (def first-negative (xs)
+            (foreach (xs x)
+                (try
+                    (checked x)
+                    (catch e
+                        (ret (get e \"message\")))))
+            (ret \"none\"))
+        (def count-valid (xs)
+            (let n 0)
+            (foreach (xs x)
+                (try
+                    (checked x)
+                    (catch e
+                        (continue)))
+                (set! n (+ n 1)))
+            (ret n))
+        (def rethrow ()
+            (try
+                (raise (dict (\"type\" \"BadInput\") (\"message\" \"oops\")))
+                (catch e
+                    (raise e))))
+        ",
+    )
+    .unwrap();
+
+    assert_eq!(
+        Value::from(vec![Value::from(12), Value::from(1)]),
+        int.call("parse", &["12"]).unwrap()
+    );
+    let caught = int.call("parse", &[Value::from(vec![1])]).unwrap();
+    assert_eq!(Value::from(1), caught.get(&Value::from(1)).unwrap());
+    let e = caught.get(&Value::from(0)).unwrap();
+    assert_eq!(
+        Value::from("OperationNotSupported"),
+        e.get(&Value::from("type")).unwrap()
+    );
+    assert_eq!(Value::from(""), e.get(&Value::from("message")).unwrap());
+    let caught = int.call("parse", &["abc"]).unwrap();
+    let e = caught.get(&Value::from(0)).unwrap();
+    assert_eq!(Value::from("Basic"), e.get(&Value::from("type")).unwrap());
+    assert_eq!(
+        Value::from("not an integer"),
+        e.get(&Value::from("message")).unwrap()
+    );
+
+    let xs = Value::from(vec![1, 0, 2]);
+    assert_eq!(
+        Value::from("none"),
+        int.call("first-negative", &[xs]).unwrap()
+    );
+    let xs = Value::from(vec![Value::from(1), Value::from(-3)]);
+    assert_eq!(
+        Value::from("negative"),
+        int.call("first-negative", &[xs]).unwrap()
+    );
+    let xs = Value::from(vec![
+        Value::from(-1),
+        Value::from(2),
+        Value::from(-3),
+        Value::from(4),
+    ]);
+    assert_eq!(Value::from(2), int.call("count-valid", &[xs]).unwrap());
+
+    let e = int.call("rethrow", &[] as &[Value]).unwrap_err();
+    assert_eq!(Lovm2ErrorTy::Custom("BadInput".to_string()), e.ty);
+    assert_eq!("oops", e.msg);
+}
+
+#[test]
+fn native_functions() {
+    use lol::NativeModule;
+
+    let mut int = interpreter();
+    int.register("double", |args: &[Value]| {
+        Ok(Value::from(args[0].as_integer_inner()? * 2))
+    })
+    .unwrap();
+    assert!(int
+        .register("double", |_: &[Value]| Ok(Value::Nil))
+        .is_err());
+
+    let geo = NativeModule::new("geo")
+        .with("area", |args: &[Value]| {
+            Ok(Value::from(
+                args[0].as_integer_inner()? * args[1].as_integer_inner()?,
+            ))
+        })
+        .with("check_size", |args: &[Value]| match args {
+            [size] if size.as_integer_inner()? > 100 => err_from_string("too large"),
+            [size] => Ok(size.clone()),
+            _ => err_from_string("expected one argument"),
+        });
+    int.register_module(geo).unwrap();
+
+    let module = create_lol_module(
+        "main",
+        "
+        (import geo)
+        (def main ()
+            (ret (double (geo-area 3 4))))
+        (def checked (x)
+            (try
+                (ret (geo-check-size x))
+                (catch e
+                    (ret (get e \"message\")))))
+        ",
+    )
+    .unwrap();
+    int.load_global(module).unwrap();
+
+    assert_eq!(Value::from(24), int.call("main", &[] as &[Value]).unwrap());
+    assert_eq!(Value::from(7), int.call("checked", &[7]).unwrap());
+    assert_eq!(
+        Value::from("too large"),
+        int.call("checked", &[700]).unwrap()
+    );
+}
+
+#[test]
+fn manifest() {
+    use lol::build::build;
+    use lol::Manifest;
+    use lovm2::module::Module;
+
+    let dir = TempDir::new("manifest");
+    let app = dir.join("app");
+    let util = dir.join("util");
+    std::fs::create_dir_all(app.join("code")).unwrap();
+    std::fs::create_dir_all(util.join("src")).unwrap();
+
+    std::fs::write(
+        app.join("lol.toml"),
+        "
+        [package]
+        name = \"app\"   # comment
+        version = \"0.1.0\"
+        entry = \"start\"
+
+        [build]
+        sources = [\"code\"]
+        output = \"out\"
+
+        [dependencies]
+        util = { path = \"../util\" }
+        ",
+    )
+    .unwrap();
+    std::fs::write(
+        app.join("code").join("start.lol"),
+        "(import util) (def main () (ret (util-inc (+ util-BASE (util-answer)))))",
+    )
+    .unwrap();
+    std::fs::write(
+        util.join("src").join("util.lol"),
+        "(def answer () (ret 40)) (defmacro inc (x) `(+ ,x 1)) (const BASE 1)",
+    )
+    .unwrap();
+
+    let manifest = Manifest::from_dir(&app).unwrap();
+    assert_eq!("app", manifest.name);
+    assert_eq!("0.1.0", manifest.version);
+    assert_eq!(Some("start".to_string()), manifest.entry);
+    assert_eq!(app.join("out"), manifest.output_dir());
+    assert_eq!("util", manifest.dependencies[0].name);
+
+    let (source, main) = build(&app).unwrap().entry_point.unwrap();
+    assert_eq!(app.join("code").join("start.lol"), source);
+    assert!(app.join("out").join("util.lolc").exists());
+
+    let mut int = interpreter();
+    int.load_main(Module::load_from_file(&main).unwrap())
+        .unwrap();
+    assert_eq!(Value::from(42), int.run().unwrap());
+
+    // projects without manifest use `src` and `target/lol`
+    let manifest = Manifest::from_dir(&util).unwrap();
+    assert_eq!(vec![util.join("src")], manifest.source_dirs());
+    assert_eq!(util.join("target/lol"), manifest.output_dir());
+    assert_eq!("util", manifest.name);
+    // relative roots are named after the directory they point to
+    let manifest = Manifest::default_for(util.join("src").join(".."));
+    assert_eq!("util", manifest.name);
+
+    let err = Manifest::parse(&app, "[package]\nname = 1\n", None).unwrap_err();
+    assert_eq!(2, err.loc.unwrap().line);
+    let err = Manifest::parse(
+        &app,
+        "[package]\nname = \"x\"\nversion = \"1\"\nfoo = \"bar\"",
+        None,
+    )
+    .unwrap_err();
+    assert!(err.msg.contains("unknown field `foo`"), "{}", err.msg);
+    assert!(Manifest::parse(&app, "[package]\nname = \"x\"", None).is_err());
+
+    // any valid toml is accepted
+    let manifest = Manifest::parse(
+        &app,
+        "[package]\nname = 'app'\nversion = \"1\"\n[build]\nsources = [\n  \"a\", # first\n  \"b\",\n]\n[dependencies.util]\npath = \"../util\"\n",
+        None,
+    )
+    .unwrap();
+    assert_eq!(vec![app.join("a"), app.join("b")], manifest.source_dirs());
+    assert_eq!(
+        app.join("../util"),
+        manifest.dependency_dir(&manifest.dependencies[0])
+    );
+}
+
+#[test]
+fn nested_modules() {
+    use lol::build::build;
+    use lol::util::module_name;
+    use lovm2::module::Module;
+
+    let dir = TempDir::new("nested");
+    std::fs::create_dir_all(dir.join("src").join("net")).unwrap();
+    std::fs::write(
+        dir.join("src").join("main.lol"),
+        "(import net.http) (def main () (ret (net-http-get 1)))",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("src").join("net").join("http.lol"),
+        "(import net.url) (import common) (def get (x) (ret (+ (net-url-port) (common-one) x)))",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("src").join("net").join("url.lol"),
+        "(def port () (ret 80))",
+    )
+    .unwrap();
+    std::fs::write(dir.join("src").join("common.lol"), "(def one () (ret 1))").unwrap();
+
+    let (_, main) = build(&dir).unwrap().entry_point.unwrap();
+    let target = dir.join("target").join("lol");
+    assert!(target.join("net").join("http.lolc").exists());
+
+    let mut int = interpreter();
+    int.load_main(Module::load_from_file(&main).unwrap())
+        .unwrap();
+    assert_eq!(Value::from(82), int.run().unwrap());
+
+    // sources are found without building as well
+    let mut int = interpreter();
+    assert_eq!(
+        Value::from(82),
+        int.run_from_path(dir.join("src").join("main.lol")).unwrap()
+    );
+
+    assert_eq!("net.http", module_name("net/http.lol"));
+
+    // without `lol.toml`, modules above the source root are not found
+    std::fs::write(dir.join("stray.lol"), "(def one () (ret 1))").unwrap();
+    std::fs::write(
+        dir.join("src").join("stray-user.lol"),
+        "(import stray) (def main () (ret (stray-one)))",
+    )
+    .unwrap();
+    let e = interpreter()
+        .run_from_path(dir.join("src").join("stray-user.lol"))
+        .unwrap_err();
+    assert!(e.msg.contains("module `stray` not found"));
+    assert!(!e.msg.contains(&dir.join("stray.lol").display().to_string()));
+}
+
+#[test]
+fn search_paths() {
+    use lol::interpreter::LOL_PATH;
+    use lol::Manifest;
+
+    let dir = TempDir::new("search");
+    let app = dir.join("app");
+    let lib = dir.join("lib");
+    std::fs::create_dir_all(&app).unwrap();
+    std::fs::create_dir_all(lib.join("text")).unwrap();
+    std::fs::write(
+        app.join("main.lol"),
+        "(import text.shout) (def main () (ret (text-shout-twice 21)))",
+    )
+    .unwrap();
+    std::fs::write(
+        lib.join("text").join("shout.lol"),
+        "(def twice (x) (ret (* x 2))) (defmacro double (x) `(* 2 ,x)) (defp secret () 1) (const PORT 8080)",
+    )
+    .unwrap();
+    std::fs::write(
+        app.join("macro.lol"),
+        "(import text.shout) (def main () (text-shout-double 21))",
+    )
+    .unwrap();
+    std::fs::write(
+        app.join("constant.lol"),
+        "(import text.shout) (def main () text-shout-PORT)",
+    )
+    .unwrap();
+    std::fs::write(
+        app.join("private.lol"),
+        "(import text.shout) (def main () (text-shout-secret))",
+    )
+    .unwrap();
+    std::fs::write(
+        app.join("broken.lol"),
+        "(import missing) (def main () (ret 1))",
+    )
+    .unwrap();
+
+    let mut int = interpreter();
+    int.add_search_path(&lib);
+    assert_eq!(vec![lib.clone()], int.search_paths());
+    assert_eq!(
+        Value::from(42),
+        int.run_from_path(app.join("main.lol")).unwrap()
+    );
+
+    // macros, constants and private functions of modules inside search paths are known
+    // when compiling the importing module
+    assert_eq!(
+        Value::from(42),
+        interpreter_with(&lib)
+            .run_from_path(app.join("macro.lol"))
+            .unwrap()
+    );
+    assert_eq!(
+        Value::from(8080),
+        interpreter_with(&lib)
+            .run_from_path(app.join("constant.lol"))
+            .unwrap()
+    );
+    let e = interpreter_with(&lib)
+        .run_from_path(app.join("private.lol"))
+        .unwrap_err();
+    assert!(e.msg.contains("private"), "{}", e.msg);
+    assert_eq!(
+        Value::from(4),
+        interpreter_with(&lib)
+            .eval("(import text.shout) (text-shout-double 2)")
+            .unwrap()
+    );
+
+    // every location is reported if a module cannot be found
+    let mut int = interpreter();
+    int.add_search_path(&lib);
+    let e = int.run_from_path(app.join("broken.lol")).unwrap_err();
+    assert_eq!(Lovm2ErrorTy::ModuleNotFound, e.ty);
+    assert!(e
+        .msg
+        .contains(&app.join("missing.lolc").display().to_string()));
+    assert!(e
+        .msg
+        .contains(&app.join("missing.lol").display().to_string()));
+    assert!(e
+        .msg
+        .contains(&lib.join("missing.lol").display().to_string()));
+    // native modules are searched where lovm2 installs them
+    assert!(e.msg.contains("lib/lovm2"));
+
+    std::env::set_var(LOL_PATH, &lib);
+    let mut int = interpreter();
+    std::env::remove_var(LOL_PATH);
+    assert_eq!(vec![lib.clone()], int.search_paths());
+    assert_eq!(
+        Value::from(42),
+        int.run_from_path(app.join("main.lol")).unwrap()
+    );
+
+    let manifest = Manifest::parse(
+        &app,
+        "[package]\nname = \"app\"\nversion = \"1\"\n[build]\nsearch-paths = [\"../lib\"]",
+        None,
+    )
+    .unwrap();
+    assert_eq!(vec![app.join("../lib")], manifest.search_dirs());
+}
+
+#[test]
+fn compile_cache() {
+    let dir = TempDir::new("cache");
+    let cache = dir.join("cache");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("main.lol"),
+        "(import util) (def main () (ret (util-double (util-answer))))",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("util.lol"),
+        "(defmacro double (x) `(* 2 ,x)) (def answer () (ret 21))",
+    )
+    .unwrap();
+
+    let run = || {
+        let mut int = interpreter();
+        int.set_cache_dir(Some(&cache));
+        int.run_from_path(dir.join("main.lol")).unwrap()
+    };
+    assert_eq!(Value::from(42), run());
+
+    let entries = std::fs::read_dir(&cache).unwrap().count();
+    assert_eq!(4, entries);
+
+    // cached modules are used as long as their sources do not change
+    let util_entry = std::fs::read_dir(&cache)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            let stamp = std::fs::read_to_string(path).unwrap_or_default();
+            stamp
+                .lines()
+                .nth(1)
+                .unwrap_or_default()
+                .ends_with("util.lol")
+        })
+        .unwrap();
+    create_lol_module("util", "(def answer () (ret 7))")
+        .unwrap()
+        .store_to_file(util_entry.with_extension("lolc"))
+        .unwrap();
+    assert_eq!(Value::from(14), run());
+
+    // entries stored with another compiler or format version are compiled again
+    let stamp = std::fs::read_to_string(&util_entry).unwrap();
+    let (_, sources) = stamp.split_once('\n').unwrap();
+    std::fs::write(&util_entry, format!("0\n{}", sources)).unwrap();
+    assert_eq!(Value::from(42), run());
+    assert_eq!(
+        lol::cache::cache_version(),
+        std::fs::read_to_string(&util_entry)
+            .unwrap()
+            .lines()
+            .next()
+            .unwrap()
+    );
+
+    // changing the macro invalidates the importing module as well
+    std::fs::write(
+        dir.join("util.lol"),
+        "(defmacro double (x) `(* 3 ,x)) (def answer () (ret 21))",
+    )
+    .unwrap();
+    assert_eq!(Value::from(63), run());
+    assert_eq!(entries, std::fs::read_dir(&cache).unwrap().count());
+}
+
+#[test]
+fn incremental_build() {
+    use lol::build::build;
+
+    let dir = TempDir::new("incremental");
+    let src = dir.join("src");
+    std::fs::create_dir_all(&src).unwrap();
+    std::fs::write(
+        src.join("main.lol"),
+        "(import util) (def main () (ret (util-answer)))",
+    )
+    .unwrap();
+    std::fs::write(src.join("util.lol"), "(def answer () (ret 42))").unwrap();
+    std::fs::write(src.join("other.lol"), "(def other () (ret 1))").unwrap();
+
+    let mut rebuilt = build(&dir).unwrap().rebuilt;
+    rebuilt.sort();
+    assert_eq!(vec!["main", "other", "util"], rebuilt);
+
+    // nothing changed
+    let report = build(&dir).unwrap();
+    assert!(report.rebuilt.is_empty());
+    assert!(report.entry_point.is_some());
+
+    // importing modules are rebuilt as well
+    std::fs::write(src.join("util.lol"), "(def answer () (ret 43))").unwrap();
+    let mut rebuilt = build(&dir).unwrap().rebuilt;
+    rebuilt.sort();
+    assert_eq!(vec!["main", "util"], rebuilt);
+
+    // missing output is recompiled
+    std::fs::remove_file(dir.join("target").join("lol").join("other.lolc")).unwrap();
+    assert_eq!(vec!["other"], build(&dir).unwrap().rebuilt);
+
+    // removed modules are dropped from the build state and output
+    std::fs::remove_file(src.join("other.lol")).unwrap();
+    assert!(build(&dir).unwrap().rebuilt.is_empty());
+    let outdir = dir.join("target").join("lol");
+    assert!(!outdir.join("other.lolc").exists());
+    let state = std::fs::read_to_string(outdir.join(lol::build::BUILD_STATE_FILE)).unwrap();
+    assert!(!state.lines().any(|line| line.starts_with("other ")));
+
+    // output of another compiler version is rebuilt completely
+    let (_, modules) = state.split_once('\n').unwrap();
+    std::fs::write(
+        outdir.join(lol::build::BUILD_STATE_FILE),
+        format!("0.0.0+0\n{}", modules),
+    )
+    .unwrap();
+    let mut rebuilt = build(&dir).unwrap().rebuilt;
+    rebuilt.sort();
+    assert_eq!(vec!["main", "util"], rebuilt);
+    let state = std::fs::read_to_string(outdir.join(lol::build::BUILD_STATE_FILE)).unwrap();
+    assert_eq!(
+        Some(lol::build::build_version().as_str()),
+        state.lines().next()
+    );
+}
+
+#[test]
+fn import_options() {
+    let dir = TempDir::new("import");
+    std::fs::create_dir_all(dir.join("text")).unwrap();
+    std::fs::write(
+        dir.join("text").join("util.lol"),
+        "(defmacro twice (x) `(* 2 ,x)) (def split (x) (ret (- x 1))) (def join (x) (ret (+ x 1)))",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("alias.lol"),
+        "(import text.util :as u) (def main () (ret (u-twice (u-join 20))))",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("only.lol"),
+        "(import text.util :only (join)) (def main () (ret (+ (join 40) (text-util-join 0))))",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("excluded.lol"),
+        "(import text.util :only (join)) (def main () (ret (text-util-split 1)))",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("separator.lol"),
+        "(import text.util) (def main () (ret (text::util::twice 21)))",
+    )
+    .unwrap();
+
+    let run = |int: &mut Interpreter, name: &str| {
+        int.set_cache_dir(None::<&str>);
+        int.run_from_path(dir.join(name))
+    };
+
+    assert_eq!(
+        Value::from(42),
+        run(&mut interpreter(), "alias.lol").unwrap()
+    );
+    assert_eq!(
+        Value::from(42),
+        run(&mut interpreter(), "only.lol").unwrap()
+    );
+
+    let e = run(&mut interpreter(), "excluded.lol").unwrap_err();
+    assert!(e
+        .msg
+        .contains("`split` is not imported from module `text.util`"));
+
+    let mut int = build(Interpreter::builder().separator("::"));
+    assert_eq!(Value::from(42), run(&mut int, "separator.lol").unwrap());
+    assert_eq!(
+        Value::from(2),
+        int.call("text::util::join", &[Value::from(1)]).unwrap()
+    );
+}
+
+#[test]
+fn visibility() {
+    let dir = TempDir::new("visibility");
+    std::fs::write(
+        dir.join("util.lol"),
+        "
+        (def handle (x)
+            (let f (fn (y) (ret (parse-get-parameters y))))
+            (ret (f x)))
+        (defp parse-get-parameters (x)
+            (ret (* x 2)))
+        ",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("main.lol"),
+        "(import util) (def main () (ret (util-handle 21)))",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("broken.lol"),
+        "(import util) (def main () (ret (util-parse-get-parameters 21)))",
+    )
+    .unwrap();
+
+    let mut int = interpreter();
+    int.set_cache_dir(None::<&str>);
+    assert_eq!(
+        Value::from(42),
+        int.run_from_path(dir.join("main.lol")).unwrap()
+    );
+    assert!(int
+        .call("util-parse-get-parameters", &[Value::from(1)])
+        .is_err());
+
+    let mut int = interpreter();
+    int.set_cache_dir(None::<&str>);
+    let e = int.run_from_path(dir.join("broken.lol")).unwrap_err();
+    assert!(e
+        .msg
+        .contains("function `parse-get-parameters` of module `util` is private"));
+
+    // modules compiled without their sources are checked when loaded
+    let main = create_lol_module(
+        "main",
+        "(import util) (def main () (ret (util-parse-get-parameters 21)))",
+    )
+    .unwrap();
+    let mut int = interpreter();
+    int.set_cache_dir(None::<&str>);
+    int.add_search_path(&dir);
+    let e = int.load_main(main).unwrap_err();
+    assert!(e
+        .msg
+        .contains("calls function `parse-get-parameters` of module `util` which is private"));
+
+    // private functions of precompiled modules are not loaded either
+    let lib = create_lol_module(
+        "lib",
+        "(defp secret () (ret 1)) (def open () (ret (secret)))",
+    )
+    .unwrap();
+    let mut int = interpreter();
+    int.load(lib).unwrap();
+    assert_eq!(Value::from(1), int.call::<Value>("lib-open", &[]).unwrap());
+    assert!(int.call::<Value>("lib-secret", &[]).is_err());
+
+    let user = create_lol_module("user", "(def main () (ret (lib-secret)))").unwrap();
+    let e = int.load(user).unwrap_err();
+    assert!(e
+        .msg
+        .contains("module `user` calls function `secret` of module `lib` which is private"));
+    // rejected modules are not added
+    assert!(int.call::<Value>("user-main", &[]).is_err());
+
+    // variables named like private functions are no calls
+    let user =
+        create_lol_module("user", "(def main () (let lib-secret 2) (ret lib-secret))").unwrap();
+    int.load(user).unwrap();
+    assert_eq!(Value::from(2), int.call::<Value>("user-main", &[]).unwrap());
+}
+
+#[test]
+fn globals() {
+    use lol::{CompileError, Transpiler};
+
+    let dir = TempDir::new("globals");
+    std::fs::write(
+        dir.join("server.lol"),
+        "
+        (def count ()
+            (set-global requests (+ requests 1))
+            (ret requests))
+        (const PORT 8080)
+        (defvar requests 0)
+        ",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("main.lol"),
+        "
+        (import server)
+        (def main ()
+            (server-count)
+            (ret (list (server-count) server-PORT)))
+        ",
+    )
+    .unwrap();
+
+    let mut int = interpreter();
+    int.set_cache_dir(None::<&str>);
+    assert_eq!(
+        Value::from(vec![2, 8080]),
+        int.run_from_path(dir.join("main.lol")).unwrap()
+    );
+    assert_eq!(
+        Value::from(8080),
+        int.call::<Value>("server-PORT", &[]).unwrap()
+    );
+
+    let e = Transpiler::new()
+        .build(
+            "main".to_string().into(),
+            "(const PORT 1) (def f () (set-global PORT 2))",
+        )
+        .unwrap_err();
+    assert!(matches!(e, CompileError::Global { .. }));
+    let e = Transpiler::new()
+        .build(
+            "main".to_string().into(),
+            "(def f () (set-global missing 2))",
+        )
+        .unwrap_err();
+    assert!(matches!(e, CompileError::Global { .. }));
+
+    // globals declared in earlier evaluations stay available
+    let mut int = interpreter();
+    int.eval("(defvar hits 0) (def hit () (set-global hits (+ hits 1)))")
+        .unwrap();
+    int.eval("(hit) (hit)").unwrap();
+    assert_eq!(Value::from(2), int.eval("hits").unwrap());
+}
+
+#[test]
+fn conditionals() {
+    let mut int = runtime(
+        "main",
+        r#"
+        (def classify (n)
+            (cond
+                ((lt n 0) (ret "negative"))
+                ((eq n 0) (ret "zero"))
+                (else (ret "positive"))))
+        (def describe (x)
+            (case x
+                (1 (ret "one"))
+                ((2 3) (ret "few"))
+                ("a" (ret "letter"))
+                (else (ret "many"))))
+        (def bounded (n)
+            (when (gt n 10)
+                (set! n (- n 1))
+                (set! n 10))
+            (unless (gt n 0)
+                (set! n 0))
+            (ret n))
+        (def empty (xs)
+            (unless xs
+                (ret "empty"))
+            (ret "items"))
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        Value::from("negative"),
+        int.call("classify", &[-3]).unwrap()
+    );
+    assert_eq!(Value::from("zero"), int.call("classify", &[0]).unwrap());
+    assert_eq!(Value::from("positive"), int.call("classify", &[3]).unwrap());
+    assert_eq!(Value::from("one"), int.call("describe", &[1]).unwrap());
+    assert_eq!(Value::from("few"), int.call("describe", &[3]).unwrap());
+    assert_eq!(Value::from("letter"), int.call("describe", &["a"]).unwrap());
+    assert_eq!(Value::from("many"), int.call("describe", &[7]).unwrap());
+    assert_eq!(Value::from(10), int.call("bounded", &[12]).unwrap());
+    assert_eq!(Value::from(5), int.call("bounded", &[5]).unwrap());
+    assert_eq!(Value::from(0), int.call("bounded", &[-5]).unwrap());
+    assert_eq!(
+        Value::from("items"),
+        int.call("empty", &[Value::from(vec![1])]).unwrap()
+    );
+    assert_eq!(
+        Value::from("empty"),
+        int.call("empty", &[Value::from(Vec::<Value>::new())])
+            .unwrap()
+    );
+    assert_eq!(Value::from("items"), int.call("empty", &[3]).unwrap());
+}
+
+#[test]
+fn labeled_loops() {
+    use lol::{CompileError, Transpiler};
+
+    let mut int = runtime(
+        "main",
+        "
+        (def odd-sum (n)
+            (let i 0)
+            (let sum 0)
+            (while (lt i n)
+                (set! i (+ i 1))
+                (if (eq (% i 2) 0)
+                    (continue))
+                (set! sum (+ sum i)))
+            (ret sum))
+        (def find-factors (n)
+            (let found 0)
+            (foreach :outer ((range 2 n) a)
+                (foreach ((range 2 n) b)
+                    (if (eq (* a b) n)
+                        (do
+                            (set! found (list a b))
+                            (break outer)))))
+            (ret found))
+        (def skip-rows (n)
+            (let count 0)
+            (let i 0)
+            (loop :rows
+                (set! i (+ i 1))
+                (if (gt i n)
+                    (break))
+                (let j 0)
+                (while (lt j n)
+                    (set! j (+ j 1))
+                    (foreach ((range 0 n) k)
+                        (if (gt j 1)
+                            (continue rows))
+                        (set! count (+ count 1)))))
+            (ret count))
+        (def countdown (n)
+            (let steps 0)
+            (while n
+                (set! n (- n 1))
+                (set! steps (+ steps 1)))
+            steps)
+        ",
+    )
+    .unwrap();
+
+    assert_eq!(Value::from(9), int.call("odd-sum", &[5]).unwrap());
+    assert_eq!(Value::from(3), int.call("countdown", &[3]).unwrap());
+    assert_eq!(
+        Value::from(vec![2, 6]),
+        int.call("find-factors", &[12]).unwrap()
+    );
+    assert_eq!(Value::from(9), int.call("skip-rows", &[3]).unwrap());
+
+    let e = Transpiler::new()
+        .build("main".to_string().into(), "(def f () (loop (break outer)))")
+        .unwrap_err();
+    assert!(matches!(e, CompileError::UnexpectedForm { .. }));
+}
+
+#[test]
+fn block_expressions() {
+    let mut int = runtime(
+        "main",
+        r#"
+        (def smaller (a b)
+            (ret (if (lt a b) a b)))
+        (def sign (n)
+            (ret (* 10 (cond
+                ((lt n 0) (- 0 1))
+                ((eq n 0) 0)
+                (else 1)))))
+        (def twice-plus-one (n)
+            (ret (do
+                (let m (* n 2))
+                (+ m 1))))
+        (def countdown (n)
+            (let total 0)
+            (while (gt (do (set! n (- n 1)) n) 0)
+                (set! total (+ total n)))
+            (ret total))
+        (def shadow (x)
+            (ret (+ x (do (let x 10) x))))
+        (def missing (n)
+            (ret (if (lt n 0) n)))
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(Value::from(2), int.call("smaller", &[2, 5]).unwrap());
+    assert_eq!(Value::from(3), int.call("smaller", &[7, 3]).unwrap());
+    assert_eq!(Value::from(-10), int.call("sign", &[-4]).unwrap());
+    assert_eq!(Value::from(0), int.call("sign", &[0]).unwrap());
+    assert_eq!(Value::from(10), int.call("sign", &[4]).unwrap());
+    assert_eq!(Value::from(7), int.call("twice-plus-one", &[3]).unwrap());
+    assert_eq!(Value::from(6), int.call("countdown", &[4]).unwrap());
+    assert_eq!(Value::from(11), int.call("shadow", &[1]).unwrap());
+    assert_eq!(Value::Nil, int.call("missing", &[1]).unwrap());
+}
+
+#[test]
+fn implicit_return() {
+    use lol::Transpiler;
+
+    let mut int = runtime(
+        "main",
+        r#"
+        (def add (a b)
+            (+ a b))
+        (def pick (a b)
+            (if (lt a b) (ret a))
+            b)
+        (def sign (n)
+            (cond
+                ((lt n 0) "negative")
+                (else "positive")))
+        (def identity (x) x)
+        (def method (name)
+            (case name
+                ("GET" 1)
+                (("PUT" "POST") 2)))
+        (def dispatch (name)
+            (case name
+                ("GET" (ret "get"))
+                (else (ret "other"))))
+        (def lambdas (i)
+            (let inc (fn (x) (+ x 1)))
+            (let get (fn () i))
+            (list (inc 1) (get)))
+        (def discarded ()
+            (list 1 2)
+            (+ 1 2)
+            3)
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(Value::from(5), int.call("add", &[2, 3]).unwrap());
+    assert_eq!(Value::from(1), int.call("pick", &[1, 4]).unwrap());
+    assert_eq!(Value::from(2), int.call("pick", &[6, 2]).unwrap());
+    assert_eq!(Value::from("positive"), int.call("sign", &[4]).unwrap());
+    assert_eq!(Value::from("x"), int.call("identity", &["x"]).unwrap());
+    assert_eq!(Value::from(2), int.call("method", &["POST"]).unwrap());
+    assert_eq!(Value::Nil, int.call("method", &["HEAD"]).unwrap());
+    assert_eq!(
+        Value::from("other"),
+        int.call("dispatch", &["PUT"]).unwrap()
+    );
+    assert_eq!(Value::from(vec![2, 7]), int.call("lambdas", &[7]).unwrap());
+    assert_eq!(
+        Value::from(3),
+        int.call("discarded", &[] as &[Value]).unwrap()
+    );
+
+    let mut trans = Transpiler::new();
+    trans
+        .build(
+            "main".to_string().into(),
+            "(def f (n) (foreach ((range n) i) (print i))) (def g () (ret 1))
+             (def h (x) (case x (1 (ret 2)) (else (ret 3))))
+             (def main () (loop (break)))",
+        )
+        .unwrap();
+    assert_eq!(1, trans.warnings().len());
+    assert!(trans.warnings()[0].msg.contains("`foreach`"));
+    assert_eq!(12, trans.warnings()[0].loc.column);
+}
+
+#[test]
+fn destructuring() {
+    let mut int = runtime(
+        "main",
+        r#"
+        (def key-value (pair)
+            (let (k v) (split pair "="))
+            (list v k))
+        (def nested ()
+            (let (a (b c)) (list 1 (list 2 3)))
+            (+ a (* b c)))
+        (def pairs ()
+            (let ((a b) (c d)) (list (list 1 2) (list 3 4)))
+            (list a b c d))
+        (def starship ()
+            (let ship (dict ("name" "X-Wing") ("passengers" 1)))
+            (let {name "name" pax "passengers"} ship)
+            (list pax name))
+        (def sequential (x)
+            (let [(x 10) (y (+ x 1))]
+                (list x y)))
+        (def shadowed (x)
+            (let [(x 10)]
+                (let x 20))
+            x)
+        (def captured ()
+            (let f (let [(n 5)] (fn (x) (ret (+ x n)))))
+            (f 1))
+        (def scoped (x)
+            (let (x 10) (y (+ x 1))
+                (list x y)))
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        Value::from(vec!["1", "a"]),
+        int.call("key-value", &["a=1"]).unwrap()
+    );
+    assert_eq!(Value::from(7), int.call("nested", &[] as &[Value]).unwrap());
+    assert_eq!(
+        Value::from(vec![1, 2, 3, 4]),
+        int.call("pairs", &[] as &[Value]).unwrap()
+    );
+    assert_eq!(
+        Value::from(vec![Value::from(1), Value::from("X-Wing")]),
+        int.call("starship", &[] as &[Value]).unwrap()
+    );
+    assert_eq!(
+        Value::from(vec![10, 11]),
+        int.call("sequential", &[1]).unwrap()
+    );
+    assert_eq!(Value::from(vec![10, 11]), int.call("scoped", &[1]).unwrap());
+    assert_eq!(Value::from(3), int.call("shadowed", &[3]).unwrap());
+    assert_eq!(
+        Value::from(6),
+        int.call("captured", &[] as &[Value]).unwrap()
+    );
+}
+
+#[test]
+fn parameters() {
+    use lol::{CompileError, Transpiler};
+
+    let mut int = runtime(
+        "main",
+        r#"
+        (def report (level &rest parts)
+            (list level parts))
+        (def connect (host (port 8080))
+            (list host port))
+        (def scaled (x (factor 2) (offset (* x factor)))
+            (+ (* x factor) offset))
+        (def by-keyword ()
+            (connect :port 9000 :host "remote"))
+        (def mixed ()
+            (scaled 1 :offset 0))
+        (def unknown-keyword ()
+            (connect "local" :prot 1))
+        (def given-twice ()
+            (connect "local" :host "remote"))
+        (def nothing ())
+        (def explicit-nil ()
+            (list (connect "local" (nothing)) (connect "local" :port (nothing))))
+        (def trace (log x)
+            (append log x)
+            x)
+        (def in-order ()
+            (let log (list))
+            (connect :port (trace log 1) (trace log 2))
+            log)
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        Value::from(vec![Value::from("info"), Value::from(vec!["a", "b"])]),
+        int.call("report", &["info", "a", "b"]).unwrap()
+    );
+    assert_eq!(
+        Value::from(vec![Value::from("info"), Value::from(Vec::<Value>::new())]),
+        int.call("report", &["info"]).unwrap()
+    );
+    assert_eq!(
+        Value::from(vec![Value::from("local"), Value::from(8080)]),
+        int.call("connect", &["local"]).unwrap()
+    );
+    assert_eq!(
+        Value::from(vec![Value::from("remote"), Value::from(9000)]),
+        int.call("by-keyword", &[] as &[Value]).unwrap()
+    );
+    assert_eq!(Value::from(12), int.call("scaled", &[3]).unwrap());
+    assert_eq!(Value::from(18), int.call("scaled", &[3, 3]).unwrap());
+    assert_eq!(Value::from(2), int.call("mixed", &[] as &[Value]).unwrap());
+    let local_nil = Value::from(vec![Value::from("local"), Value::Nil]);
+    assert_eq!(
+        Value::from(vec![local_nil.clone(), local_nil]),
+        int.call("explicit-nil", &[] as &[Value]).unwrap()
+    );
+    assert_eq!(
+        Value::from(vec![1, 2]),
+        int.call("in-order", &[] as &[Value]).unwrap()
+    );
+
+    let argument_error = Lovm2ErrorTy::Custom("ArgumentError".to_string());
+    let e = int.call("connect", &[] as &[Value]).unwrap_err();
+    assert_eq!(argument_error, e.ty);
+
+    // functions with required parameters only are checked as well
+    let mut add = runtime(
+        "main",
+        "(def add (a b) (- a b)) (def swapped () (add :b 1 :a 2))",
+    )
+    .unwrap();
+    assert_eq!(
+        Value::from(1),
+        add.call("swapped", &[] as &[Value]).unwrap()
+    );
+    let e = add.call("add", &[1, 2, 3]).unwrap_err();
+    assert_eq!(argument_error, e.ty);
+    let e = add.call("add", &[1]).unwrap_err();
+    assert_eq!(argument_error, e.ty);
+    let e = int.call("connect", &[1, 2, 3]).unwrap_err();
+    assert_eq!(argument_error, e.ty);
+    let e = int.call("unknown-keyword", &[] as &[Value]).unwrap_err();
+    assert_eq!(argument_error, e.ty);
+    let e = int.call("given-twice", &[] as &[Value]).unwrap_err();
+    assert_eq!(argument_error, e.ty);
+
+    let e = Transpiler::new()
+        .build("main".to_string().into(), "(def f ((a 1) b) a)")
+        .unwrap_err();
+    assert!(matches!(e, CompileError::UnexpectedForm { .. }));
+}
+
+#[test]
+fn scopes() {
+    use lol::{CompileError, Transpiler};
+
+    let mut int = runtime(
+        "main",
+        "
+        (def branch (x)
+            (if (gt x 0)
+                (let x 100))
+            x)
+        (def item ()
+            (let i 10)
+            (foreach ((range 3) i)
+                (let j i))
+            i)
+        (def total (n)
+            (let sum 0)
+            (foreach ((range n) i)
+                (set! sum (+ sum i)))
+            sum)
+        (def inner (x)
+            (let y 1)
+            (when (gt x 0)
+                (let y 2)
+                (set! y (+ y x)))
+            y)
+        (def captured (x)
+            (let f (fn () (ret x)))
+            (set! x (+ x 1))
+            (list (f) x))
+        ",
+    )
+    .unwrap();
+
+    assert_eq!(Value::from(5), int.call("branch", &[5]).unwrap());
+    assert_eq!(Value::from(10), int.call("item", &[] as &[Value]).unwrap());
+    assert_eq!(Value::from(6), int.call("total", &[4]).unwrap());
+    assert_eq!(Value::from(1), int.call("inner", &[3]).unwrap());
+    assert_eq!(Value::from(vec![1, 2]), int.call("captured", &[1]).unwrap());
+
+    for source in [
+        "(def f () (set! x 1))",
+        "(def f (n) (if n (let x 1)) (set! x 2))",
+        "(def f (xs) (foreach (xs x) (print x)) (set! x 2))",
+        "(defvar count 0) (def f () (set! count 1))",
+    ] {
+        let e = Transpiler::new()
+            .build("main".to_string().into(), source)
+            .unwrap_err();
+        assert!(matches!(e, CompileError::Unbound { .. }));
+    }
+}
+
+#[test]
+fn compile_errors() {
+    use lol::{CompileError, Transpiler};
+
+    let build = |src: &str| {
+        let mut trans = Transpiler::new();
+        trans.build("main".to_string().into(), src).unwrap_err()
+    };
+
+    match build("(def)") {
+        CompileError::OperandCount { name, got, loc, .. } => {
+            assert_eq!("def", name);
+            assert_eq!(0, got);
+            assert_eq!((1, 1), (loc.line, loc.column));
+        }
+        e => panic!("unexpected error {:?}", e),
+    }
+
+    match build("(def main ()\n    (let x))") {
+        CompileError::OperandCount { name, got, loc, .. } => {
+            assert_eq!("let", name);
+            assert_eq!(1, got);
+            assert_eq!((2, 5), (loc.line, loc.column));
+            assert_eq!((17, 24), loc.span);
+        }
+        e => panic!("unexpected error {:?}", e),
+    }
+
+    assert!(matches!(
+        build("(def main () (foreach (x) (print x)))"),
+        CompileError::UnexpectedForm { .. }
+    ));
+    assert!(matches!(
+        build("1"),
+        CompileError::UnexpectedToplevel { .. }
+    ));
+    assert!(matches!(build("(def main ()"), CompileError::Parse { .. }));
+    assert!(matches!(
+        build("(def main () ())"),
+        CompileError::UnexpectedForm { .. }
+    ));
+    assert!(matches!(
+        build("(def main () (ret (+ 1)))"),
+        CompileError::OperandCount { .. }
+    ));
+}
+
+#[test]
+fn builtin_macros() {
+    use std::collections::BTreeSet;
+
+    let readme = include_str!("../README.md");
+    let section = readme.split("## Builtin Macros").nth(1).unwrap();
+    let listed: BTreeSet<&str> = section.split("```").nth(1).unwrap().lines().collect();
+    let builtins: BTreeSet<&str> = lol::transpiler::builtin_macros().collect();
+    assert!(builtins.contains("defmacro"));
+    assert!(builtins.contains("quasiquote"));
+    assert_eq!(
+        builtins,
+        listed.into_iter().filter(|l| !l.is_empty()).collect()
+    );
+}
+
+#[test]
+fn diagnostics() {
+    use lol::{Diagnostic, Transpiler};
+
+    let src = "(def main ()\n    (forach ((range 3) i)\n        (print i)))";
+    let mut trans = Transpiler::new();
+    let e = trans.build("main".to_string().into(), src).unwrap_err();
+    let rendered = Diagnostic::from_compile_error(&e, Some(src)).render(Some(src));
+
+    assert_eq!(
+        "error: expected symbol, got form `range`\n \
+         --> 2:14\n  \
+         |\n\
+         2 |     (forach ((range 3) i)\n  \
+         |              ^^^^^^^^^\n \
+         = hint: did you mean `foreach`?\n",
+        rendered
+    );
+    let e = lol::create_lol_runtime("main", src).err().unwrap();
+    assert_eq!("expected symbol, got form `range`", e.msg);
+    assert_eq!(Some("did you mean `foreach`?".to_string()), e.hint);
+
+    let src = "(def main ()\n    (fac 3))\n(def fak (n)\n    (ret n))";
+    let mut int = interpreter();
+    let e = int
+        .load_main(create_lol_module("main", src).unwrap())
+        .and_then(|_| int.run());
+    let diagnostic = Diagnostic::from_runtime_error(&e.unwrap_err(), None, Some(src));
+
+    assert_eq!("cannot find `fac`", diagnostic.msg);
+    assert_eq!(2, diagnostic.loc.unwrap().line);
+    assert_eq!(Some("did you mean `fak`?".to_string()), diagnostic.hint);
+
+    // failing conversions and builtins are located at their call
+    let src = "(def main ()\n    (let x (list 1))\n    (int \"abc\"))";
+    let mut int = interpreter();
+    let e = int
+        .load_main(create_lol_module("main", src).unwrap())
+        .and_then(|_| int.run())
+        .unwrap_err();
+    let rendered = int.diagnose(&e, None, Some(src)).render(Some(src));
+    assert_eq!(
+        "error: Basic: not an integer\n \
+         --> 3:6\n  \
+         |\n\
+         3 |     (int \"abc\"))\n  \
+         |      ^^^\n",
+        rendered
+    );
+    let src = "(def main ()\n    (let x (list 1))\n    (len 1))";
+    let mut int = interpreter();
+    let e = int
+        .load_main(create_lol_module("main", src).unwrap())
+        .and_then(|_| int.run())
+        .unwrap_err();
+    assert_eq!(3, int.diagnose(&e, None, Some(src)).loc.unwrap().line);
+    // errors caught earlier are not mistaken for it
+    let src = "(def main () (try (len 1) (catch e (let y 0))) (raise \"oops\"))";
+    let mut int = interpreter();
+    let e = int
+        .load_main(create_lol_module("main", src).unwrap())
+        .and_then(|_| int.run())
+        .unwrap_err();
+    assert!(int.diagnose(&e, None, Some(src)).loc.is_none());
+}
+
+#[test]
+fn eval_source() {
+    let mut int = interpreter();
+
+    assert_eq!(Value::from(5), int.eval("(+ 2 3)").unwrap());
+    assert_eq!(
+        Value::from(7),
+        int.eval("(def inc (x) (ret (+ x 1))) (inc 6)").unwrap()
+    );
+
+    // definitions and macros stay available
+    int.eval("(defmacro twice (x) `(+ ,x ,x))").unwrap();
+    assert_eq!(Value::from(8), int.eval("(twice (inc 3))").unwrap());
+    assert_eq!(Value::from(2), int.call("inc", &[1]).unwrap());
+    assert_eq!(Value::Nil, int.eval("(def inc (x) (ret x))").unwrap());
+    assert_eq!(Value::from(1), int.eval("(inc 1)").unwrap());
+    assert_eq!(
+        Value::from("yes"),
+        int.eval("(if (lt 1 2) \"yes\" \"no\")").unwrap()
+    );
+
+    let e = int.eval("(let x)").unwrap_err();
+    assert_eq!(Lovm2ErrorTy::Custom("CompileError".to_string()), e.ty);
+    assert!(int.eval("(undefined 1)").is_err());
+    assert_eq!(Value::from(3), int.eval("(inc 3)").unwrap());
+}
+
+#[test]
+fn execution_limits() {
+    use lol::limits::{INTERRUPTED, STEP_LIMIT_EXCEEDED, TIMEOUT};
+    use std::time::Duration;
+
+    let mut int = runtime(
+        "main",
+        "
+        (def spin ()
+            (loop))
+        (def guarded ()
+            (try
+                (spin)
+                (catch e
+                    (ret 1))))
+        (def escape ()
+            (try
+                (loop)
+                (finally
+                    (ret \"escaped\"))))
+        (def count (n)
+            (let i 0)
+            (foreach ((range n) x)
+                (set! i (+ i 1)))
+            (ret i))
+        ",
+    )
+    .unwrap();
+    let custom = |ty: &str| Lovm2ErrorTy::Custom(ty.to_string());
+
+    int.set_step_limit(Some(100));
+    assert_eq!(Value::from(10), int.call("count", &[10]).unwrap());
+    let e = int.call("count", &[1000]).unwrap_err();
+    assert_eq!(custom(STEP_LIMIT_EXCEEDED), e.ty);
+    // limits cannot be caught by scripts
+    let e = int.call("guarded", &[] as &[Value]).unwrap_err();
+    assert_eq!(custom(STEP_LIMIT_EXCEEDED), e.ty);
+    let e = int.call("escape", &[] as &[Value]).unwrap_err();
+    assert_eq!(custom(STEP_LIMIT_EXCEEDED), e.ty);
+    // every call gets a new budget
+    assert_eq!(Value::from(10), int.call("count", &[10]).unwrap());
+    int.set_step_limit(None);
+
+    int.set_timeout(Some(Duration::from_millis(50)));
+    let e = int.call("spin", &[] as &[Value]).unwrap_err();
+    assert_eq!(custom(TIMEOUT), e.ty);
+    int.set_timeout(None);
+
+    let handle = int.interrupt_handle();
+    let interrupter = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        handle.interrupt();
+    });
+    let e = int.call("guarded", &[] as &[Value]).unwrap_err();
+    assert_eq!(custom(INTERRUPTED), e.ty);
+    interrupter.join().unwrap();
+
+    assert_eq!(Value::from(3), int.call("count", &[3]).unwrap());
+}
+
+#[test]
+fn sandbox() {
+    use lol::sandbox::PERMISSION_DENIED;
+
+    let denied = Lovm2ErrorTy::Custom(PERMISSION_DENIED.to_string());
+
+    let mut int = build(Interpreter::builder().sandboxed());
+    assert_eq!(Value::from(2), int.eval("(len (list 1 2))").unwrap());
+    let e = int.eval("(open_file \"/etc/passwd\")").unwrap_err();
+    assert_eq!(denied, e.ty);
+    assert!(e.msg.contains("open_file"));
+    assert_eq!(denied, int.eval("(print 1)").unwrap_err().ty);
+    // builtins are denied unless they are known to be safe
+    assert_eq!(
+        denied,
+        int.eval("(new_request \"http://localhost\")")
+            .unwrap_err()
+            .ty
+    );
+    assert_eq!(
+        Value::from("A"),
+        int.eval("(to_upper (trim \" a \"))").unwrap()
+    );
+    assert_eq!(denied, int.eval("(import util) 1").unwrap_err().ty);
+
+    let dir = TempDir::new("sandbox");
+    std::fs::write(dir.join("util.lol"), "(def answer () (ret 42))").unwrap();
+    std::fs::write(dir.join("secret.lol"), "(def answer () (ret 0))").unwrap();
+    std::fs::write(
+        dir.join("main.lol"),
+        "(import util) (def main () (print (util-answer)) (ret (util-answer)))",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("other.lol"),
+        "(import secret) (def main () (ret (secret-answer)))",
+    )
+    .unwrap();
+
+    let sandboxed = || {
+        build(
+            Interpreter::builder()
+                .allow_module("util")
+                .allow_function("print"),
+        )
+    };
+    assert_eq!(
+        Value::from(42),
+        sandboxed().run_from_path(dir.join("main.lol")).unwrap()
+    );
+    let e = sandboxed()
+        .run_from_path(dir.join("other.lol"))
+        .unwrap_err();
+    assert_eq!(denied, e.ty);
+    assert!(e.msg.contains("secret"));
+}
+
+#[test]
+fn repl_session() {
+    use lol::repl::{is_complete, Repl};
+
+    let mut repl = Repl::new();
+
+    assert_eq!(
+        Value::Nil,
+        repl.eval("(def add (a b) (ret (+ a b)))").unwrap()
+    );
+    assert_eq!(Value::from(3), repl.eval("(add 1 2)").unwrap());
+
+    // redefining replaces the previous function
+    repl.eval("(def add (a b) (ret (* a b)))").unwrap();
+    assert_eq!(Value::from(12), repl.eval("(add 3 4)").unwrap());
+    assert_eq!(&["add".to_string()], repl.functions());
+
+    assert!(repl.eval("(let x)").is_err());
+    assert!(repl.eval("(undefined 1)").is_err());
+    assert_eq!(Value::from(5), repl.eval("(add 1 5) (+ 2 3)").unwrap());
+
+    repl.reset();
+    assert!(repl.functions().is_empty());
+    assert!(repl.eval("(add 1 2)").is_err());
+
+    assert!(is_complete("(def f (x)\n (ret x))"));
+    assert!(!is_complete("(def f (x)\n"));
+    assert!(!is_complete("(print \"(\""));
+    assert!(is_complete("(print \")\") ; (("));
+    assert!(!is_complete("(print \"\\\")\n"));
+    assert!(is_complete("(print \"\\\"\")"));
+
+    // variables bound at the prompt stay available
+    repl.eval("(let x 5)").unwrap();
+    assert_eq!(Value::from(6), repl.eval("(+ x 1)").unwrap());
+    repl.eval("(set! x 7)").unwrap();
+    assert_eq!(Value::from(7), repl.eval("x").unwrap());
+    repl.eval("(let (a b) (list 1 2))").unwrap();
+    assert_eq!(Value::from(3), repl.eval("(+ a b)").unwrap());
+
+    // definitions stay even if the input fails afterwards
+    assert!(repl.eval("(def inc (x) (+ x 1)) (undefined)").is_err());
+    assert_eq!(Value::from(8), repl.eval("(inc x)").unwrap());
+    assert!(repl.functions().contains(&"inc".to_string()));
+}